@@ -1,9 +1,17 @@
 use clap::{AppSettings, Clap};
+use std::collections::HashMap;
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
 
-use client::{Client, DResult, JobId, JobStartRequest, OutputStream, TlsConfig};
+use client::{
+    parse_size, Client, Completed, CpuLimit, CpuSet, DResult, IoLimit, JobId, JobStartRequest,
+    Mount, NetworkMode, OutputStream, PathRule, TerminalInput, TerminalSize, TlsConfig, Tmpfs,
+    User,
+};
 
 #[derive(Clap)]
 #[clap(version, author)]
@@ -25,12 +33,420 @@ struct Opts {
 }
 
 #[derive(Clap)]
+#[allow(clippy::large_enum_variant)] // Parsed only once
 enum Operation {
-    Start { path: String, args: Vec<String> },
-    Stop { jobid: String },
-    Status { jobid: String },
-    Wait { jobid: String },
-    Output { jobid: String },
+    Start(StartOpts),
+    /// Start a job in a pseudo-terminal, and attach the local terminal to it until it completes
+    Shell(StartOpts),
+    Stop {
+        jobid: String,
+    },
+    Pause {
+        jobid: String,
+    },
+    Resume {
+        jobid: String,
+    },
+    Status {
+        jobid: String,
+    },
+    Wait {
+        jobid: String,
+    },
+    Output {
+        jobid: String,
+    },
+}
+
+#[derive(Clap)]
+struct StartOpts {
+    /// Limit CPU usage to this many cores, e.g. 0.5
+    #[clap(long)]
+    cpus: Option<f64>,
+    /// Limit memory usage, e.g. 512M or 2G
+    #[clap(long, parse(try_from_str = parse_size))]
+    memory: Option<u64>,
+    /// Maximum number of processes in the job
+    #[clap(long)]
+    max_processes: Option<u64>,
+    /// Run only on these CPUs, e.g. 0-3,8
+    #[clap(long)]
+    cpuset_cpus: Option<String>,
+    /// Use memory only from these NUMA nodes, e.g. 0
+    #[clap(long)]
+    cpuset_mems: Option<String>,
+    /// Reserve the CPUs of --cpuset-cpus for this job only
+    #[clap(long, requires = "cpuset-cpus")]
+    exclusive_cpus: bool,
+    /// Don't run the job in its own PID namespace
+    #[clap(long)]
+    host_pid_namespace: bool,
+    /// Root filesystem directory on the server
+    #[clap(long)]
+    rootfs: Option<String>,
+    /// Don't mount the root filesystem read-only
+    #[clap(long)]
+    writable_rootfs: bool,
+    /// OCI runtime bundle directory on the server. Its settings are used for the options not given.
+    #[clap(long)]
+    bundle: Option<String>,
+    /// Network access: none, loopback, host or bridge
+    #[clap(long, default_value = "none")]
+    network: NetworkMode,
+    /// Run as root of a user namespace, mapped to unprivileged IDs on the server
+    #[clap(long)]
+    user_namespace: bool,
+    /// Hostname seen by the job, defaults to the job id
+    #[clap(long)]
+    hostname: Option<String>,
+    /// Seccomp profile of the server, e.g. default, strict or unconfined
+    #[clap(long)]
+    seccomp_profile: Option<String>,
+    /// Keep a capability, e.g. CAP_NET_BIND_SERVICE. All others are dropped.
+    #[clap(long = "cap-add", number_of_values = 1)]
+    capabilities: Vec<String>,
+    /// Set an rlimit, e.g. nofile=1024 or core=unlimited
+    #[clap(long, number_of_values = 1, parse(try_from_str = parse_rlimit))]
+    rlimit: Vec<(String, u64)>,
+    /// Run as this user, a name or a numeric uid
+    #[clap(long)]
+    user: Option<String>,
+    /// Primary group, defaults to the group of the user
+    #[clap(long, requires = "user")]
+    group: Option<String>,
+    /// Supplementary group
+    #[clap(long = "group-add", number_of_values = 1, requires = "user")]
+    groups: Vec<String>,
+    /// Bind-mount a server path, e.g. /srv/cache:/cache:ro,nodev. Options are ro, rw, nosuid, nodev and noexec.
+    #[clap(long = "mount", number_of_values = 1, parse(try_from_str = parse_mount))]
+    mounts: Vec<Mount>,
+    /// Mount a private tmpfs with a maximum size, e.g. /tmp:512M
+    #[clap(long, number_of_values = 1, parse(try_from_str = parse_tmpfs))]
+    tmpfs: Vec<Tmpfs>,
+    /// Allow reading and executing under a path. Nothing else is accessible if any paths are allowed.
+    #[clap(long, number_of_values = 1)]
+    allow_read: Vec<String>,
+    /// Allow full access under a path
+    #[clap(long, number_of_values = 1)]
+    allow_write: Vec<String>,
+    /// Limit read rate of a device, e.g. sda:10M
+    #[clap(long, number_of_values = 1, parse(try_from_str = parse_device_rate))]
+    device_read_bps: Vec<(String, u64)>,
+    /// Limit write rate of a device, e.g. sda:10M
+    #[clap(long, number_of_values = 1, parse(try_from_str = parse_device_rate))]
+    device_write_bps: Vec<(String, u64)>,
+    /// Limit read operations per second of a device, e.g. sda:1000
+    #[clap(long, number_of_values = 1, parse(try_from_str = parse_device_rate))]
+    device_read_iops: Vec<(String, u64)>,
+    /// Limit write operations per second of a device, e.g. sda:1000
+    #[clap(long, number_of_values = 1, parse(try_from_str = parse_device_rate))]
+    device_write_iops: Vec<(String, u64)>,
+    /// Set an environment variable, e.g. RUST_LOG=debug
+    #[clap(long, number_of_values = 1, parse(try_from_str = parse_env))]
+    env: Vec<(String, String)>,
+    /// Read environment variables from a local file with KEY=VAL lines. --env overrides these.
+    #[clap(long, number_of_values = 1)]
+    env_file: Vec<PathBuf>,
+    /// Start from the environment of the server, instead of only PATH
+    #[clap(long)]
+    inherit_env: bool,
+    /// Working directory in the job filesystem, an absolute path
+    #[clap(long)]
+    workdir: Option<String>,
+    /// Forward local stdin to the job until it ends. Otherwise the job reads from /dev/null.
+    #[clap(long)]
+    stdin: bool,
+    /// Executable, or the process of the bundle if not given
+    #[clap(required_unless_present = "bundle")]
+    path: Option<String>,
+    args: Vec<String>,
+}
+
+impl StartOpts {
+    fn into_request(self) -> io::Result<JobStartRequest> {
+        let mut io: Vec<IoLimit> = Vec::new();
+        for (device, value) in self.device_read_bps {
+            IoLimit::entry(&mut io, device).read_bps = value;
+        }
+        for (device, value) in self.device_write_bps {
+            IoLimit::entry(&mut io, device).write_bps = value;
+        }
+        for (device, value) in self.device_read_iops {
+            IoLimit::entry(&mut io, device).read_iops = value;
+        }
+        for (device, value) in self.device_write_iops {
+            IoLimit::entry(&mut io, device).write_iops = value;
+        }
+
+        let cpuset = if self.cpuset_cpus.is_some() || self.cpuset_mems.is_some() {
+            Some(CpuSet {
+                cpus: self.cpuset_cpus.unwrap_or_default(),
+                mems: self.cpuset_mems.unwrap_or_default(),
+                exclusive: self.exclusive_cpus,
+            })
+        } else {
+            None
+        };
+
+        let path_rules = self
+            .allow_read
+            .into_iter()
+            .map(|path| PathRule {
+                path,
+                writable: false,
+            })
+            .chain(self.allow_write.into_iter().map(|path| PathRule {
+                path,
+                writable: true,
+            }))
+            .collect();
+
+        let mut env = HashMap::new();
+        for path in &self.env_file {
+            env.extend(read_env_file(path)?);
+        }
+        env.extend(self.env);
+
+        let (group, groups) = (self.group, self.groups);
+        let user = self.user.map(|user| User {
+            user,
+            group: group.unwrap_or_default(),
+            groups,
+        });
+
+        Ok(JobStartRequest {
+            path: self.path.unwrap_or_default(),
+            args: self.args,
+            cpu: self.cpus.map(CpuLimit::cpus),
+            memory_bytes: self.memory.unwrap_or(0),
+            io,
+            max_processes: self.max_processes.unwrap_or(0),
+            cpuset,
+            host_pid_namespace: self.host_pid_namespace,
+            rootfs: self.rootfs.unwrap_or_default(),
+            writable_rootfs: self.writable_rootfs,
+            network: self.network as i32,
+            user_namespace: self.user_namespace,
+            hostname: self.hostname.unwrap_or_default(),
+            seccomp_profile: self.seccomp_profile.unwrap_or_default(),
+            capabilities: self.capabilities,
+            rlimits: self.rlimit.into_iter().collect(),
+            user,
+            mounts: self.mounts,
+            tmpfs: self.tmpfs,
+            bundle: self.bundle.unwrap_or_default(),
+            path_rules,
+            cwd: self.workdir.unwrap_or_default(),
+            env,
+            inherit_env: self.inherit_env,
+            stdin: self.stdin,
+            tty: None,
+        })
+    }
+}
+
+/// Parse a `device:value` pair, where the value is a size
+fn parse_device_rate(s: &str) -> Result<(String, u64), String> {
+    let (device, value) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Expected device:value, got {:?}", s))?;
+    Ok((device.to_owned(), parse_size(value)?))
+}
+
+/// Parse a `resource=value` pair, where the value is a size or `unlimited`
+fn parse_rlimit(s: &str) -> Result<(String, u64), String> {
+    let (resource, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected resource=value, got {:?}", s))?;
+    let value = if value == "unlimited" {
+        u64::MAX
+    } else {
+        parse_size(value)?
+    };
+    Ok((resource.to_owned(), value))
+}
+
+/// Parse a `source:destination[:options]` mount
+fn parse_mount(s: &str) -> Result<Mount, String> {
+    let mut parts = s.splitn(3, ':');
+    let (source, destination) = match (parts.next(), parts.next()) {
+        (Some(source), Some(destination)) => (source, destination),
+        _ => {
+            return Err(format!(
+                "Expected source:destination[:options], got {:?}",
+                s
+            ))
+        }
+    };
+    let mut mount = Mount {
+        source: source.to_owned(),
+        destination: destination.to_owned(),
+        ..Default::default()
+    };
+    for option in parts
+        .next()
+        .unwrap_or("")
+        .split(',')
+        .filter(|o| !o.is_empty())
+    {
+        match option {
+            "ro" => mount.read_only = true,
+            "rw" => mount.read_only = false,
+            "nosuid" => mount.nosuid = true,
+            "nodev" => mount.nodev = true,
+            "noexec" => mount.noexec = true,
+            _ => return Err(format!("Unknown mount option {:?}", option)),
+        }
+    }
+    Ok(mount)
+}
+
+/// Parse a `KEY=VAL` environment variable
+fn parse_env(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected KEY=VAL, got {:?}", s))?;
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// Read `KEY=VAL` lines from a file. Empty lines and lines starting with `#` are skipped.
+fn read_env_file(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let content = fs::read_to_string(path)?;
+    let vars = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_env)
+        .collect::<Result<_, _>>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e)))?;
+    Ok(vars)
+}
+
+/// Parse a `destination:size` tmpfs
+fn parse_tmpfs(s: &str) -> Result<Tmpfs, String> {
+    let (destination, size) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Expected destination:size, got {:?}", s))?;
+    Ok(Tmpfs {
+        destination: destination.to_owned(),
+        size_bytes: parse_size(size)?,
+    })
+}
+
+/// Send local stdin to a channel in chunks, until it ends
+async fn read_stdin(tx: mpsc::Sender<Vec<u8>>) {
+    let mut stdin = tokio::io::stdin();
+    let mut buffer = vec![0; 8192];
+    loop {
+        match stdin.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(n) => {
+                if tx.send(buffer[..n].to_vec()).await.is_err() {
+                    break;
+                }
+            }
+            Err(err) => {
+                eprintln!("Reading stdin failed: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+/// Size of the local terminal, or 24x80 if there's no terminal
+fn terminal_size() -> TerminalSize {
+    let mut winsize: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDIN_FILENO, libc::TIOCGWINSZ, &mut winsize) } < 0 {
+        return TerminalSize { rows: 24, cols: 80 };
+    }
+    TerminalSize {
+        rows: winsize.ws_row.into(),
+        cols: winsize.ws_col.into(),
+    }
+}
+
+/// Puts the local terminal into raw mode, so that all keystrokes are sent as is.
+/// The original mode is restored when dropped.
+struct RawMode {
+    original: libc::termios,
+}
+impl RawMode {
+    /// Returns `None` if stdin is not a terminal
+    fn enable() -> io::Result<Option<Self>> {
+        if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+            return Ok(None);
+        }
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = original;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(Self { original }))
+    }
+}
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
+
+/// Send local stdin and terminal resizes to a channel, until stdin ends
+async fn read_terminal_input(tx: mpsc::Sender<TerminalInput>) {
+    let mut stdin = tokio::io::stdin();
+    let mut resized = signal(SignalKind::window_change()).expect("Signal handler failed");
+    let mut buffer = vec![0; 1024];
+    loop {
+        let input = tokio::select! {
+            read = stdin.read(&mut buffer) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => TerminalInput::Data(buffer[..n].to_vec()),
+            },
+            _ = resized.recv() => TerminalInput::Resize(terminal_size()),
+        };
+        if tx.send(input).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Attach the local terminal to a job until it completes.
+/// Returns the exit code of the job, or 128 + signal number like shells do.
+async fn shell(client: &mut Client, jobid: JobId) -> DResult<i32> {
+    let (input_tx, input_rx) = mpsc::channel(2);
+    let (output_tx, mut output_rx) = mpsc::channel(2);
+    let mut stdout = io::stdout();
+    if let Err(err) = client.attach(jobid, input_rx, output_tx).await {
+        // Jobs that complete before attaching can't be attached to, but their output is kept
+        if client.status(jobid).await?.completed.is_none() {
+            return Err(err);
+        }
+        let (tx, mut rx) = mpsc::channel(2);
+        client.output(jobid, tx).await?;
+        while let Some(event) = rx.recv().await {
+            stdout.write_all(&event?.1)?;
+        }
+        stdout.flush()?;
+    } else {
+        let raw_mode = RawMode::enable()?;
+        tokio::spawn(read_terminal_input(input_tx));
+
+        while let Some(output) = output_rx.recv().await {
+            stdout.write_all(&output?)?;
+            stdout.flush()?;
+        }
+        drop(raw_mode);
+    }
+
+    let status = client.wait(jobid).await?;
+    Ok(match status.completed {
+        Some(Completed::StatusCode(code)) => code,
+        Some(Completed::Signal(signal)) => 128 + signal,
+        None => 1,
+    })
 }
 
 #[tokio::main]
@@ -55,11 +471,30 @@ async fn inner() -> DResult<()> {
     let mut client = Client::connect(opts.server, tls).await?;
 
     match opts.subcmd {
-        Operation::Start { path, args } => {
-            let jobid = client.start(JobStartRequest { path, args }).await?;
+        Operation::Start(start) => {
+            let forward_stdin = start.stdin;
+            let jobid = client.start(start.into_request()?).await?;
             println!("{}", jobid);
+            if forward_stdin {
+                let (tx, rx) = mpsc::channel(2);
+                tokio::spawn(read_stdin(tx));
+                client.write_stdin(jobid, rx, true).await?;
+            }
+        }
+        Operation::Shell(start) => {
+            let mut req = start.into_request()?;
+            req.tty = Some(terminal_size());
+            if let Ok(term) = std::env::var("TERM") {
+                req.env.entry("TERM".to_owned()).or_insert(term);
+            }
+            let jobid = client.start(req).await?;
+            let code = shell(&mut client, jobid).await?;
+            // Local stdin may still be read in the background, which would keep the runtime alive
+            std::process::exit(code);
         }
         Operation::Stop { jobid } => client.stop(JobId::parse(&jobid)?).await?,
+        Operation::Pause { jobid } => client.pause(JobId::parse(&jobid)?).await?,
+        Operation::Resume { jobid } => client.resume(JobId::parse(&jobid)?).await?,
         Operation::Status { jobid } => {
             println!("{}", client.status(JobId::parse(&jobid)?).await?);
         }
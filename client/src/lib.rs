@@ -2,8 +2,9 @@
 
 use std::convert::TryInto;
 
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::time::{sleep, Duration};
+use tokio_stream::StreamExt;
 use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
 
 use common::t_service_client::TServiceClient;
@@ -13,8 +14,21 @@ mod error;
 
 // Re-exports
 pub use self::error::{DResult, Error};
+pub use common::job_status::Completed;
 pub use common::output_event::Stream as OutputStream;
-pub use common::{JobId, JobStartRequest};
+pub use common::{
+    parse_size, CpuLimit, CpuSet, IoLimit, JobId, JobStartRequest, Mount, NetworkMode, PathRule,
+    TerminalSize, Tmpfs, User,
+};
+
+/// Input for the terminal of an attached job
+#[derive(Debug, Clone)]
+pub enum TerminalInput {
+    /// Keystrokes
+    Data(Vec<u8>),
+    /// The local terminal was resized
+    Resize(TerminalSize),
+}
 
 #[derive(Debug, Clone)]
 pub struct TlsConfig {
@@ -64,10 +78,9 @@ impl Client {
     }
 
     /// Cancels a job.
-    /// This is done by sending a `SIGKILL` to the underlying process.
+    /// This is done by sending a `SIGKILL` to all processes of the job, including descendants.
     /// If the job has already terminated or stop has been called before, then this is a no-op.
-    /// This is done asynchronously, and can return before the process has terminated.
-    /// If you must wait until the job has stopped, do so by calling `wait`.
+    /// Returns after the job has completed and all of its processes are gone.
     pub async fn stop(&mut self, jobid: JobId) -> DResult<()> {
         self.client
             .stop(tonic::Request::new(TargetJobId {
@@ -78,6 +91,30 @@ impl Client {
         Ok(())
     }
 
+    /// Pauses a job by freezing all of its processes.
+    /// Returns after the processes have been frozen. Pausing a paused job is a no-op.
+    pub async fn pause(&mut self, jobid: JobId) -> DResult<()> {
+        self.client
+            .pause(tonic::Request::new(TargetJobId {
+                jobid: jobid.to_bytes(),
+            }))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Resumes a paused job.
+    /// Returns after the processes have been thawed. Resuming a running job is a no-op.
+    pub async fn resume(&mut self, jobid: JobId) -> DResult<()> {
+        self.client
+            .resume(tonic::Request::new(TargetJobId {
+                jobid: jobid.to_bytes(),
+            }))
+            .await?;
+
+        Ok(())
+    }
+
     /// Get job status, i.e. is it running, and the status code if the job has completed.
     /// If the job has been terminated with a signal, that is reported instead.
     pub async fn status(&mut self, jobid: JobId) -> DResult<JobStatus> {
@@ -105,6 +142,79 @@ impl Client {
         }
     }
 
+    /// Write data from an mpsc queue to stdin of a job, until the sender is dropped.
+    /// If `close` is set, stdin of the job is closed after all data has been written,
+    /// so an empty queue can be used to just close it.
+    /// The job must have been started with `stdin` set.
+    pub async fn write_stdin(
+        &mut self,
+        jobid: JobId,
+        rx: Receiver<Vec<u8>>,
+        close: bool,
+    ) -> DResult<()> {
+        let jobid = jobid.to_bytes();
+        // The job id is sent with every chunk, as the first one may be the final one
+        let final_chunk = StdinChunk {
+            jobid: jobid.clone(),
+            data: Vec::new(),
+            close,
+        };
+        let chunks = tokio_stream::wrappers::ReceiverStream::new(rx)
+            .map(move |data| StdinChunk {
+                jobid: jobid.clone(),
+                data,
+                close: false,
+            })
+            .chain(tokio_stream::once(final_chunk));
+        self.client.write_stdin(tonic::Request::new(chunks)).await?;
+        Ok(())
+    }
+
+    /// Attach to the pseudo-terminal of a job, which must have been started with `tty` set.
+    /// Input from the mpsc queue is sent to the terminal until the sender is dropped.
+    /// Output of the terminal is streamed to `tx` from the moment the job was started,
+    /// and the stream is closed when the job completes.
+    pub async fn attach(
+        &mut self,
+        jobid: JobId,
+        rx: Receiver<TerminalInput>,
+        tx: Sender<Result<Vec<u8>, tonic::Status>>,
+    ) -> DResult<()> {
+        use common::attach_input::Input;
+
+        // The first message only selects the job
+        let select = AttachInput {
+            jobid: jobid.to_bytes(),
+            input: None,
+        };
+        let input = tokio_stream::once(select).chain(
+            tokio_stream::wrappers::ReceiverStream::new(rx).map(|input| AttachInput {
+                jobid: Vec::new(),
+                input: Some(match input {
+                    TerminalInput::Data(data) => Input::Data(data),
+                    TerminalInput::Resize(size) => Input::Resize(size),
+                }),
+            }),
+        );
+        let response = self.client.attach(tonic::Request::new(input)).await?;
+
+        let mut inner = response.into_inner();
+        tokio::spawn(async move {
+            loop {
+                let r = match inner.message().await {
+                    Ok(Some(msg)) => Ok(msg.output),
+                    Ok(None) => break,
+                    Err(err) => Err(err),
+                };
+                let is_err = r.is_err();
+                if tx.send(r).await.is_err() || is_err {
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+
     /// Stream output of a job to an mpsc queue.
     /// Stream is automatically closed when the process completes and all output has been streamed.
     /// All calls stream the whole output history from the moment the process was started.
@@ -8,14 +8,119 @@ tonic::include_proto!("common");
 
 impl fmt::Display for JobStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use job_status::Completed;
+        use job_status::{Completed, Reason};
         if let Some(result) = &self.completed {
             match result {
-                Completed::StatusCode(code) => write!(f, "Completed({})", code),
-                Completed::Signal(signal) => write!(f, "Signal({})", signal),
+                Completed::StatusCode(code) => write!(f, "Completed({})", code)?,
+                Completed::Signal(signal) => write!(f, "Signal({})", signal)?,
             }
+            match Reason::from_i32(self.reason) {
+                Some(Reason::OutOfMemory) => write!(f, ", out of memory")?,
+                Some(Reason::SeccompViolation) => write!(f, ", seccomp violation")?,
+                Some(Reason::CpuTimeLimit) => write!(f, ", CPU time limit exceeded")?,
+                Some(Reason::FileSizeLimit) => write!(f, ", file size limit exceeded")?,
+                _ => {}
+            }
+        } else if self.paused {
+            write!(f, "Paused")?;
         } else {
-            write!(f, "Running")
+            write!(f, "Running")?;
+        }
+        if self.process_limit_reached {
+            write!(f, ", process limit reached")?;
+        }
+        if !self.ip_address.is_empty() {
+            write!(f, ", address {}", self.ip_address)?;
+        }
+        if let Some(mapping) = &self.id_mapping {
+            write!(
+                f,
+                ", ids 0-{} mapped to {}-{}",
+                mapping.size.saturating_sub(1),
+                mapping.host_id,
+                mapping
+                    .host_id
+                    .saturating_add(mapping.size.saturating_sub(1))
+            )?;
+        }
+        if let Some(usage) = &self.usage {
+            write!(
+                f,
+                ", cpu user {:.3}s system {:.3}s, memory peak {} bytes, io read {} written {} bytes",
+                usage.cpu_user_us as f64 / 1e6,
+                usage.cpu_system_us as f64 / 1e6,
+                usage.memory_peak_bytes,
+                usage.io_read_bytes,
+                usage.io_write_bytes
+            )?;
+            if usage.processes_peak != 0 {
+                write!(f, ", at most {} processes", usage.processes_peak)?;
+            }
+        }
+        for usage in &self.tmpfs {
+            write!(
+                f,
+                ", tmpfs {} used {} of {} bytes",
+                usage.destination, usage.used_bytes, usage.size_bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl CpuLimit {
+    /// Period used when `period_us` is not set
+    pub const DEFAULT_PERIOD_US: u64 = 100_000;
+
+    /// Limit to a number of CPUs, e.g. `1.5` allows using one and a half cores
+    pub fn cpus(cpus: f64) -> Self {
+        Self {
+            quota_us: (cpus * Self::DEFAULT_PERIOD_US as f64) as u64,
+            period_us: Self::DEFAULT_PERIOD_US,
+        }
+    }
+}
+
+impl IoLimit {
+    /// Get limits of a device, adding a new entry if required
+    pub fn entry(io: &mut Vec<IoLimit>, device: String) -> &mut IoLimit {
+        if let Some(index) = io.iter().position(|limit| limit.device == device) {
+            &mut io[index]
+        } else {
+            io.push(IoLimit {
+                device,
+                ..Default::default()
+            });
+            io.last_mut().unwrap()
+        }
+    }
+}
+
+/// Parse a byte count with an optional K, M or G suffix (powers of 1024)
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("Invalid size {:?}", s))?;
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size {:?} is too large", s))
+}
+
+impl std::str::FromStr for NetworkMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "loopback" => Ok(Self::Loopback),
+            "host" => Ok(Self::Host),
+            "bridge" => Ok(Self::Bridge),
+            _ => Err(format!("Unknown network mode {:?}", s)),
         }
     }
 }
@@ -19,6 +19,7 @@ async fn test_concurrent_output() -> Result<(), Box<dyn std::error::Error>> {
         .start(JobStartRequest {
             path: "./common/tests/scripts/slow-output.sh".to_owned(),
             args: Vec::new(),
+            ..Default::default()
         })
         .await?;
 
@@ -2,7 +2,7 @@
 
 mod helpers;
 
-use helpers::TestServer;
+use helpers::{job_cgroups, TempDir, TestServer};
 
 use common::JobId;
 
@@ -30,7 +30,7 @@ fn test_simple_stop() {
     println!("Status {}", status);
     assert!(status.contains("Running"));
 
-    cli!(s, "stop", &job_id);
+    let _ = cli!(s, "stop", &job_id);
 
     let status = cli!(s, "status", &job_id);
     assert!(status.contains("Signal"));
@@ -49,8 +49,8 @@ fn test_concurrent() {
     let status = cli!(s, "status", &job_b);
     assert!(status.contains("Running"));
 
-    cli!(s, "stop", &job_a);
-    cli!(s, "stop", &job_b);
+    let _ = cli!(s, "stop", &job_a);
+    let _ = cli!(s, "stop", &job_b);
 
     let status = cli!(s, "status", &job_a);
     assert!(status.contains("Signal"));
@@ -74,12 +74,636 @@ fn test_exit_code() {
 fn test_nonexistent_binary() {
     let s = TestServer::new();
 
-    cli!(s, "start", "NONEXISTENT");
+    let _ = cli!(s, "start", "NONEXISTENT");
 }
 
 #[test]
 #[should_panic(expected = "No such job")]
 fn test_nonexistent_job() {
     let s = TestServer::new();
-    cli!(s, "status", &JobId::new().to_string());
+    let _ = cli!(s, "status", &JobId::new().to_string());
+}
+
+/// Start a job with a limit, and read the control file of its cgroup.
+/// `None` if the controller isn't available on this machine.
+fn cgroup_limit(s: &TestServer, option: &str, value: &str, file: &str) -> Option<String> {
+    let controller = file.split('.').next().unwrap();
+    let cgroups = match job_cgroups(controller) {
+        Some(cgroups) => cgroups,
+        None => {
+            eprintln!("Skipped, the {} controller is not available", controller);
+            return None;
+        }
+    };
+    let job_id = cli!(s, "start", option, value, "sleep", "infinity");
+    let limit = std::fs::read_to_string(cgroups.join(&job_id).join(file)).unwrap();
+    let _ = cli!(s, "stop", &job_id);
+    Some(limit)
+}
+
+#[test]
+fn test_cpu_limit() {
+    let s = TestServer::new();
+    if let Some(limit) = cgroup_limit(&s, "--cpus", "0.5", "cpu.max") {
+        assert_eq!(limit.trim(), "50000 100000");
+    }
+}
+
+#[test]
+fn test_memory_limit() {
+    let s = TestServer::new();
+    if let Some(limit) = cgroup_limit(&s, "--memory", "64M", "memory.max") {
+        assert_eq!(limit.trim(), "67108864");
+    }
+}
+
+#[test]
+fn test_process_limit() {
+    let s = TestServer::new();
+    if let Some(limit) = cgroup_limit(&s, "--max-processes", "10", "pids.max") {
+        assert_eq!(limit.trim(), "10");
+    }
+}
+
+#[test]
+fn test_cpuset_limit() {
+    let s = TestServer::new();
+    if let Some(limit) = cgroup_limit(&s, "--cpuset-cpus", "0", "cpuset.cpus") {
+        assert_eq!(limit.trim(), "0");
+    }
+}
+
+#[test]
+#[should_panic(expected = "No such block device")]
+fn test_io_limit_nonexistent_device() {
+    let s = TestServer::new();
+    let _ = cli!(s, "start", "--device-read-bps", "NONEXISTENT:1M", "ls");
+}
+
+#[test]
+fn test_pid_namespace() {
+    let s = TestServer::new();
+
+    let job_id = cli!(s, "start", "sh", "--", "-c", "echo $$");
+    let _ = cli!(s, "wait", &job_id);
+    let output = cli!(s, "output", &job_id);
+    assert_eq!(output, "1");
+
+    let job_id = cli!(
+        s,
+        "start",
+        "--host-pid-namespace",
+        "sh",
+        "--",
+        "-c",
+        "echo $$"
+    );
+    let _ = cli!(s, "wait", &job_id);
+    let output = cli!(s, "output", &job_id);
+    assert_ne!(output, "1");
+}
+
+#[test]
+#[should_panic(expected = "FailedPrecondition")]
+fn test_nonexistent_rootfs() {
+    let s = TestServer::new();
+    let _ = cli!(s, "start", "--rootfs", "/NONEXISTENT", "ls");
+}
+
+#[test]
+#[should_panic(expected = "PermissionDenied")]
+fn test_rootfs_not_allowed() {
+    let s = TestServer::new();
+    let _ = cli!(s, "start", "--rootfs", "/tmp", "ls");
+}
+
+#[test]
+fn test_network_none() {
+    let s = TestServer::new();
+
+    let job_id = cli!(s, "start", "cat", "/proc/net/dev");
+    let _ = cli!(s, "wait", &job_id);
+    let output = cli!(s, "output", &job_id);
+    let interfaces: Vec<&str> = output.lines().skip(2).collect();
+    assert_eq!(interfaces.len(), 1);
+    assert!(interfaces[0].trim().starts_with("lo:"));
+}
+
+#[test]
+#[should_panic(expected = "PermissionDenied")]
+fn test_network_host_denied() {
+    let s = TestServer::new();
+    let _ = cli!(s, "start", "--network", "host", "ls");
+}
+
+#[test]
+fn test_network_bridge() {
+    let s = TestServer::new();
+
+    let job_id = cli!(s, "start", "--network", "bridge", "sleep", "infinity");
+    let status = cli!(s, "status", &job_id);
+    assert!(status.contains("address 10.88.0."));
+
+    let _ = cli!(s, "stop", &job_id);
+}
+
+#[test]
+fn test_network_bridge_host_isolated() {
+    let s = TestServer::new();
+
+    // Connections are queued without accepting them
+    let listener = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let script = format!("echo > /dev/tcp/10.88.0.1/{}", port);
+    let job_id = cli!(
+        s,
+        "start",
+        "--network",
+        "bridge",
+        "--",
+        "timeout",
+        "2",
+        "bash",
+        "-c",
+        &script
+    );
+    let status = cli!(s, "wait", &job_id);
+    assert!(!status.contains("Completed(0)"), "{}", status);
+}
+
+#[test]
+fn test_network_bridge_jobs_connected() {
+    let s = TestServer::new();
+
+    let server_script = "import socket
+s = socket.socket()
+s.bind(('0.0.0.0', 8000))
+s.listen()
+print(s.accept()[0].recv(100).decode())";
+    let server_id = cli!(
+        s,
+        "start",
+        "--network",
+        "bridge",
+        "--",
+        "python3",
+        "-c",
+        server_script
+    );
+    let status = cli!(s, "status", &server_id);
+    let address = status
+        .split("address ")
+        .nth(1)
+        .unwrap()
+        .split(',')
+        .next()
+        .unwrap();
+
+    // Connecting is retried until the other job listens, but the gateway must stay unreachable
+    let listener = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let script = format!(
+        "for i in $(seq 50); do echo hello > /dev/tcp/{}/8000 && ok=1 && break; sleep 0.1; done; \
+         [ -n \"$ok\" ] || exit 1; \
+         timeout 2 bash -c 'echo > /dev/tcp/10.88.0.1/{}' && exit 2; exit 0",
+        address, port
+    );
+    let client_id = cli!(
+        s,
+        "start",
+        "--network",
+        "bridge",
+        "--",
+        "bash",
+        "-c",
+        &script
+    );
+    let status = cli!(s, "wait", &client_id);
+    assert!(status.contains("Completed(0)"), "{}", status);
+
+    let _ = cli!(s, "wait", &server_id);
+    assert_eq!(cli!(s, "output", &server_id), "hello");
+}
+
+#[test]
+#[should_panic(expected = "not mapped in the user namespace")]
+fn test_user_namespace_unmapped_user() {
+    // Too few IDs for nobody (65534)
+    let s = TestServer::with_config(&[("ids_per_job = 65536", "ids_per_job = 1000")]);
+    let _ = cli!(s, "start", "--user-namespace", "--user", "nobody", "ls");
+}
+
+#[test]
+fn test_user_namespace() {
+    let s = TestServer::new();
+
+    let job_id = cli!(s, "start", "--user-namespace", "cat", "/proc/self/uid_map");
+    let _ = cli!(s, "wait", &job_id);
+    let output = cli!(s, "output", &job_id);
+    let fields: Vec<&str> = output.split_whitespace().collect();
+    assert_eq!(fields[0], "0");
+    assert_ne!(fields[1], "0");
+
+    let status = cli!(s, "status", &job_id);
+    assert!(status.contains(&format!("mapped to {}-", fields[1])));
+}
+
+#[test]
+fn test_hostname() {
+    let s = TestServer::new();
+
+    let job_id = cli!(s, "start", "cat", "/proc/sys/kernel/hostname");
+    let _ = cli!(s, "wait", &job_id);
+    let output = cli!(s, "output", &job_id);
+    assert_eq!(output, job_id);
+
+    let job_id = cli!(
+        s,
+        "start",
+        "--hostname",
+        "job1",
+        "cat",
+        "/proc/sys/kernel/hostname"
+    );
+    let _ = cli!(s, "wait", &job_id);
+    let output = cli!(s, "output", &job_id);
+    assert_eq!(output, "job1");
+}
+
+#[test]
+fn test_seccomp_profiles() {
+    let s = TestServer::new();
+
+    // The default profile denies creating namespaces
+    let job_id = cli!(s, "start", "unshare", "--", "--user", "true");
+    let status = cli!(s, "wait", &job_id);
+    assert!(status.starts_with("Completed(1),"));
+
+    let job_id = cli!(
+        s,
+        "start",
+        "--seccomp-profile",
+        "strict",
+        "unshare",
+        "--",
+        "--user",
+        "true"
+    );
+    let status = cli!(s, "wait", &job_id);
+    assert!(status.contains("seccomp violation"));
+}
+
+#[test]
+fn test_capabilities() {
+    let s = TestServer::new();
+
+    let job_id = cli!(s, "start", "grep", "CapEff", "/proc/self/status");
+    let _ = cli!(s, "wait", &job_id);
+    let output = cli!(s, "output", &job_id);
+    assert!(output.ends_with("0000000000000000"));
+
+    let job_id = cli!(
+        s,
+        "start",
+        "--cap-add",
+        "CAP_NET_BIND_SERVICE",
+        "grep",
+        "CapEff",
+        "/proc/self/status"
+    );
+    let _ = cli!(s, "wait", &job_id);
+    let output = cli!(s, "output", &job_id);
+    assert!(output.ends_with("0000000000000400"));
+}
+
+#[test]
+#[should_panic(expected = "PermissionDenied")]
+fn test_capability_denied() {
+    let s = TestServer::new();
+    let _ = cli!(s, "start", "--cap-add", "CAP_SYS_ADMIN", "ls");
+}
+
+#[test]
+fn test_rlimits() {
+    let s = TestServer::new();
+
+    let job_id = cli!(
+        s,
+        "start",
+        "--rlimit",
+        "nofile=100",
+        "sh",
+        "--",
+        "-c",
+        "ulimit -n"
+    );
+    let _ = cli!(s, "wait", &job_id);
+    let output = cli!(s, "output", &job_id);
+    assert_eq!(output, "100");
+
+    let job_id = cli!(
+        s,
+        "start",
+        "--rlimit",
+        "cpu=1",
+        "sh",
+        "--",
+        "-c",
+        "while :; do :; done"
+    );
+    let status = cli!(s, "wait", &job_id);
+    assert!(status.contains("CPU time limit exceeded"));
+}
+
+#[test]
+fn test_stop_past_cpu_soft_limit() {
+    let s = TestServer::new();
+
+    // Soft limit of one second, and the hard limit a second later
+    let job_id = cli!(
+        s,
+        "start",
+        "--rlimit",
+        "cpu=1",
+        "sh",
+        "--",
+        "-c",
+        "while :; do :; done"
+    );
+    let cpu_user = |status: &str| -> f64 {
+        let value = status.split("cpu user ").nth(1).expect("No CPU usage");
+        value.split('s').next().unwrap().parse().unwrap()
+    };
+    while cpu_user(&cli!(s, "status", &job_id)) < 1.2 {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    let _ = cli!(s, "stop", &job_id);
+
+    let status = cli!(s, "status", &job_id);
+    assert!(status.contains("Signal(9)"), "{}", status);
+    assert!(!status.contains("CPU time limit exceeded"), "{}", status);
+}
+
+#[test]
+fn test_run_as_user() {
+    let s = TestServer::new();
+
+    let job_id = cli!(
+        s,
+        "start",
+        "--user",
+        "nobody",
+        "--group-add",
+        "daemon",
+        "id"
+    );
+    let _ = cli!(s, "wait", &job_id);
+    let output = cli!(s, "output", &job_id);
+    assert!(output.starts_with("uid=65534(nobody) gid=65534"));
+    assert!(output.contains("1(daemon)"));
+}
+
+#[test]
+#[should_panic(expected = "PermissionDenied")]
+fn test_run_as_user_denied() {
+    let s = TestServer::new();
+    let _ = cli!(s, "start", "--user", "root", "id");
+}
+
+#[test]
+#[should_panic(expected = "PermissionDenied")]
+fn test_mount_source_denied() {
+    let s = TestServer::new();
+    let _ = cli!(s, "start", "--mount", "/etc:/mnt:ro", "ls", "/mnt");
+}
+
+#[test]
+#[should_panic(expected = "must not contain `..`")]
+fn test_mount_destination_parent_dir() {
+    let s = TestServer::new();
+    let _ = cli!(s, "start", "--tmpfs", "/tmp/../etc:1M", "ls");
+}
+
+#[test]
+fn test_tmpfs() {
+    let s = TestServer::new();
+
+    let job_id = cli!(
+        s,
+        "start",
+        "--tmpfs",
+        "/tmp:1M",
+        "dd",
+        "if=/dev/zero",
+        "of=/tmp/scratch",
+        "bs=1K",
+        "count=2000"
+    );
+
+    let status = cli!(s, "wait", &job_id);
+    assert!(status.contains("Completed(1)")); // The tmpfs is full
+    assert!(status.contains("tmpfs /tmp used 1048576 of 1048576 bytes"));
+}
+
+#[test]
+fn test_oci_bundle() {
+    // Bundles must be under a directory allowed by the server config
+    let bundles = TempDir::new("bundles");
+    let s = TestServer::with_config(&[(
+        r#"bundles = ["/var/lib/job-runner/bundles"]"#,
+        &format!("bundles = [{:?}]", bundles.path),
+    )]);
+    let bundle = bundles.path.join("hello");
+    std::fs::create_dir(&bundle).unwrap();
+    std::fs::copy(
+        "tests/bundles/hello/config.json",
+        bundle.join("config.json"),
+    )
+    .unwrap();
+    let job_id = cli!(s, "start", "--bundle", bundle.to_str().unwrap());
+
+    let status = cli!(s, "wait", &job_id);
+    assert!(status.contains("Completed(0)"));
+
+    let output = cli!(s, "output", &job_id);
+    assert_eq!(output, "hello\n/tmp");
+}
+
+#[test]
+#[should_panic(expected = "PermissionDenied")]
+fn test_oci_bundle_not_allowed() {
+    let s = TestServer::new();
+
+    let bundle = std::env::current_dir().unwrap().join("tests/bundles/hello");
+    let _ = cli!(s, "start", "--bundle", bundle.to_str().unwrap());
+}
+
+#[test]
+fn test_landlock() {
+    let s = TestServer::new();
+
+    let start = |path: &str| {
+        cli!(
+            s,
+            "start",
+            "--allow-read",
+            "/usr",
+            "--allow-read",
+            "/bin",
+            "--allow-read",
+            "/lib",
+            "--allow-read",
+            "/lib64",
+            "ls",
+            path
+        )
+    };
+
+    let status = cli!(s, "wait", &start("/usr"));
+    assert!(status.contains("Completed(0)"));
+
+    let status = cli!(s, "wait", &start("/etc"));
+    assert!(status.contains("Completed(2)")); // Permission denied
+}
+
+#[test]
+fn test_resource_usage() {
+    let s = TestServer::new();
+
+    let job_id = cli!(s, "start", "sleep", "infinity");
+    let status = cli!(s, "status", &job_id);
+    assert!(status.contains("Running, cpu user"));
+
+    let _ = cli!(s, "stop", &job_id);
+    let status = cli!(s, "wait", &job_id);
+    assert!(status.contains("Signal(9), cpu user"));
+    assert!(!status.contains("memory peak 0 bytes"));
+}
+
+#[test]
+fn test_pause_resume() {
+    let s = TestServer::new();
+
+    let job_id = cli!(s, "start", "sleep", "infinity");
+
+    let _ = cli!(s, "pause", &job_id);
+    let status = cli!(s, "status", &job_id);
+    assert!(status.starts_with("Paused"));
+
+    let _ = cli!(s, "resume", &job_id);
+    let status = cli!(s, "status", &job_id);
+    assert!(status.starts_with("Running"));
+
+    // Paused jobs can still be stopped
+    let _ = cli!(s, "pause", &job_id);
+    let _ = cli!(s, "stop", &job_id);
+    let status = cli!(s, "wait", &job_id);
+    assert!(status.starts_with("Signal(9)"));
+}
+
+/// Whether a process has exited, or doesn't exist at all
+fn has_exited(pid: &str) -> bool {
+    let pid: u32 = pid.parse().expect("Not a pid");
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat
+            .rsplit(')')
+            .next()
+            .unwrap()
+            .trim_start()
+            .starts_with('Z'),
+        Err(_) => true,
+    }
+}
+
+#[test]
+fn test_stop_process_tree() {
+    let s = TestServer::new();
+
+    // Without a PID namespace, the background process would outlive the shell
+    let job_id = cli!(
+        s,
+        "start",
+        "--host-pid-namespace",
+        "sh",
+        "--",
+        "-c",
+        "sleep infinity & echo $!; wait"
+    );
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let _ = cli!(s, "stop", &job_id);
+    let status = cli!(s, "status", &job_id);
+    assert!(status.starts_with("Signal(9)"));
+    let output = cli!(s, "output", &job_id);
+    assert!(has_exited(&output), "Process {} is still running", output);
+
+    // Processes left behind are killed when the job completes
+    let job_id = cli!(
+        s,
+        "start",
+        "--host-pid-namespace",
+        "sh",
+        "--",
+        "-c",
+        "sleep infinity & echo $!"
+    );
+    let status = cli!(s, "wait", &job_id);
+    assert!(status.starts_with("Completed(0)"));
+    let output = cli!(s, "output", &job_id);
+    assert!(has_exited(&output), "Process {} is still running", output);
+}
+
+#[test]
+fn test_environment() {
+    let s = TestServer::new();
+
+    let env_file = std::env::temp_dir().join(format!("env-{}", std::process::id()));
+    std::fs::write(&env_file, "# Comment\nGREETING=hello\nNAME=file\n").unwrap();
+
+    let job_id = cli!(
+        s,
+        "start",
+        "--env-file",
+        env_file.to_str().unwrap(),
+        "--env",
+        "NAME=world",
+        "--workdir",
+        "/usr",
+        "sh",
+        "--",
+        "-c",
+        "echo $GREETING $NAME $JOB_ID ${HOME-unset} $(pwd)"
+    );
+    std::fs::remove_file(&env_file).unwrap();
+    let status = cli!(s, "wait", &job_id);
+    assert!(status.starts_with("Completed(0)"));
+    let output = cli!(s, "output", &job_id);
+    assert_eq!(output, format!("hello world {} unset /usr", job_id));
+
+    let job_id = cli!(s, "start", "--inherit-env", "sh", "--", "-c", "echo $HOME");
+    let _ = cli!(s, "wait", &job_id);
+    let output = cli!(s, "output", &job_id);
+    assert!(!output.is_empty());
+}
+
+#[test]
+fn test_stdin() {
+    let s = TestServer::new();
+
+    // The CLI gets no stdin here, so the job sees the end of it right away
+    let job_id = cli!(s, "start", "--stdin", "cat");
+    let status = cli!(s, "wait", &job_id);
+    assert!(status.starts_with("Completed(0)"));
+}
+
+#[test]
+fn test_shell() {
+    let s = TestServer::new();
+
+    // Without a local terminal, the CLI doesn't switch to raw mode
+    let output = cli!(s, "shell", "sh", "--", "-c", "tty");
+    assert!(
+        output.starts_with("/dev/pts/"),
+        "Unexpected output {:?}",
+        output
+    );
 }
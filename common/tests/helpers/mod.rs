@@ -1,18 +1,59 @@
 #![allow(dead_code)]
 
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command};
 use std::sync::Once;
 
 /// Makes sure the binary dependencies are only built once
 static BUILD_DONE: Once = Once::new();
 
+/// Directory removed when dropped
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let port = portpicker::pick_unused_port().expect("No ports free");
+        let path = std::env::temp_dir().join(format!("job-runner-test-{}-{}", name, port));
+        std::fs::create_dir(&path).expect("Creating temporary directory");
+        Self { path }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
 pub struct TestServer {
     child: Child,
     pub addr: String,
+    /// Config file, if not the default one
+    _config: Option<TempDir>,
 }
 
 impl TestServer {
     pub fn new() -> Self {
+        Self::start(None)
+    }
+
+    /// Start with a copy of `config/server.toml`, where each `(old, new)` text is replaced
+    pub fn with_config(replace: &[(&str, &str)]) -> Self {
+        let dir = TempDir::new("config");
+        let mut config = std::fs::read_to_string("../config/server.toml").unwrap();
+        for (old, new) in replace {
+            assert!(config.contains(old), "{:?} not in config", old);
+            config = config.replace(old, new);
+        }
+        std::fs::write(dir.path.join("server.toml"), config).unwrap();
+        // Relative to the config file
+        std::fs::copy("../config/seccomp.json", dir.path.join("seccomp.json")).unwrap();
+        Self::start(Some(dir))
+    }
+
+    fn start(config: Option<TempDir>) -> Self {
         BUILD_DONE.call_once(|| {
             let build_status = Command::new("cargo")
                 .arg("build")
@@ -25,7 +66,11 @@ impl TestServer {
         let port = portpicker::pick_unused_port().expect("No ports free");
         let addr = format!("127.0.0.1:{}", port);
 
-        let child = Command::new("target/debug/server")
+        let mut command = Command::new("target/debug/server");
+        if let Some(dir) = &config {
+            command.arg("--config").arg(dir.path.join("server.toml"));
+        }
+        let child = command
             .arg(&addr)
             .current_dir("..")
             .env("RUST_LOG", "server=debug")
@@ -34,7 +79,11 @@ impl TestServer {
 
         // Give the server a moment to wake up
         std::thread::sleep(std::time::Duration::from_millis(50));
-        Self { child, addr }
+        Self {
+            child,
+            addr,
+            _config: config,
+        }
     }
 }
 
@@ -53,6 +102,24 @@ pub fn client_tls_config(client_number: usize) -> client::TlsConfig {
     }
 }
 
+/// Parent of the job cgroups of a `TestServer`, if the controller is enabled for them
+pub fn job_cgroups(controller: &str) -> Option<PathBuf> {
+    let mounts = std::fs::read_to_string("/proc/mounts").ok()?;
+    let mount = mounts.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, mount_point, "cgroup2", ..] => Some(*mount_point),
+            _ => None,
+        }
+    })?;
+    let root = Path::new(mount).join("job-runner");
+    let enabled = std::fs::read_to_string(root.join("cgroup.subtree_control")).ok()?;
+    if !enabled.split_whitespace().any(|c| c == controller) {
+        return None;
+    }
+    Some(root)
+}
+
 #[macro_export]
 macro_rules! cli {
     ($server:expr, $($a:expr),*) => {{
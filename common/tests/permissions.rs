@@ -4,7 +4,7 @@ mod helpers;
 
 use helpers::{client_tls_config, TestServer};
 
-use client::{Client, JobStartRequest};
+use client::{Client, JobStartRequest, User};
 
 #[tokio::test]
 async fn test_permission_denied() -> Result<(), Box<dyn std::error::Error>> {
@@ -17,6 +17,7 @@ async fn test_permission_denied() -> Result<(), Box<dyn std::error::Error>> {
         .start(JobStartRequest {
             path: "sleep".to_owned(),
             args: vec!["infinity".to_owned()],
+            ..Default::default()
         })
         .await?;
 
@@ -26,5 +27,41 @@ async fn test_permission_denied() -> Result<(), Box<dyn std::error::Error>> {
         "Expected permission denied error, instead succeeded"
     );
 
+    let result = client2.pause(jobid).await;
+    assert!(
+        result.is_err(),
+        "Expected permission denied error, instead succeeded"
+    );
+
+    let result = client2.resume(jobid).await;
+    assert!(
+        result.is_err(),
+        "Expected permission denied error, instead succeeded"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_not_allowed() -> Result<(), Box<dyn std::error::Error>> {
+    let s = TestServer::new();
+
+    let mut client2 = Client::connect(format!("grpcs://{}", s.addr), client_tls_config(2)).await?;
+
+    let result = client2
+        .start(JobStartRequest {
+            path: "id".to_owned(),
+            user: Some(User {
+                user: "nobody".to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await;
+    assert!(
+        result.is_err(),
+        "Expected permission denied error, instead succeeded"
+    );
+
     Ok(())
 }
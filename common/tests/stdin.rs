@@ -0,0 +1,124 @@
+#![deny(unused_must_use)]
+
+mod helpers;
+
+use helpers::{client_tls_config, TestServer};
+
+use client::{Client, JobStartRequest, OutputStream};
+
+#[tokio::test]
+async fn test_write_stdin() -> Result<(), Box<dyn std::error::Error>> {
+    let s = TestServer::new();
+
+    let mut client = Client::connect(format!("grpcs://{}", s.addr), client_tls_config(1)).await?;
+
+    let jobid = client
+        .start(JobStartRequest {
+            path: "cat".to_owned(),
+            stdin: true,
+            ..Default::default()
+        })
+        .await?;
+
+    // Stdin stays open between streams
+    let (tx, rx) = tokio::sync::mpsc::channel(2);
+    tx.send(b"hello ".to_vec()).await?;
+    drop(tx);
+    client.write_stdin(jobid, rx, false).await?;
+    assert!(client.status(jobid).await?.completed.is_none());
+
+    let (tx, rx) = tokio::sync::mpsc::channel(2);
+    tx.send(b"world".to_vec()).await?;
+    drop(tx);
+    client.write_stdin(jobid, rx, true).await?;
+
+    let status = client.wait(jobid).await?;
+    assert_eq!(status.to_string().split(',').next(), Some("Completed(0)"));
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+    client.output(jobid, tx).await?;
+    let mut stdout = Vec::new();
+    while let Some(event) = rx.recv().await {
+        let (stream, data) = event?;
+        if stream == OutputStream::Stdout {
+            stdout.extend(data);
+        }
+    }
+    assert_eq!(stdout, b"hello world");
+
+    // Writing after closing fails
+    let (_, rx) = tokio::sync::mpsc::channel(1);
+    let result = client.write_stdin(jobid, rx, true).await;
+    assert!(result.is_err(), "Expected an error, instead succeeded");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stdin_not_requested() -> Result<(), Box<dyn std::error::Error>> {
+    let s = TestServer::new();
+
+    let mut client = Client::connect(format!("grpcs://{}", s.addr), client_tls_config(1)).await?;
+
+    // Reads /dev/null
+    let jobid = client
+        .start(JobStartRequest {
+            path: "cat".to_owned(),
+            ..Default::default()
+        })
+        .await?;
+    let status = client.wait(jobid).await?;
+    assert_eq!(status.to_string().split(',').next(), Some("Completed(0)"));
+
+    let (_, rx) = tokio::sync::mpsc::channel(1);
+    let result = client.write_stdin(jobid, rx, true).await;
+    assert!(result.is_err(), "Expected an error, instead succeeded");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_write_stdin_idle_stream() -> Result<(), Box<dyn std::error::Error>> {
+    let s = TestServer::new();
+
+    let mut client = Client::connect(format!("grpcs://{}", s.addr), client_tls_config(1)).await?;
+    let mut idle_client =
+        Client::connect(format!("grpcs://{}", s.addr), client_tls_config(1)).await?;
+
+    let jobid = client
+        .start(JobStartRequest {
+            path: "cat".to_owned(),
+            stdin: true,
+            ..Default::default()
+        })
+        .await?;
+
+    // Stays open after the first chunk
+    let (idle_tx, rx) = tokio::sync::mpsc::channel(2);
+    idle_tx.send(b"hello ".to_vec()).await?;
+    let idle = tokio::spawn(async move { idle_client.write_stdin(jobid, rx, false).await });
+
+    let (tx, mut output) = tokio::sync::mpsc::channel(2);
+    client.output(jobid, tx).await?;
+    let (_, data) = output.recv().await.unwrap()?;
+    assert_eq!(data, b"hello ");
+
+    // Not blocked by the idle stream
+    let (tx, rx) = tokio::sync::mpsc::channel(2);
+    tx.send(b"world".to_vec()).await?;
+    drop(tx);
+    tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        client.write_stdin(jobid, rx, true),
+    )
+    .await??;
+
+    let status = client.wait(jobid).await?;
+    assert_eq!(status.to_string().split(',').next(), Some("Completed(0)"));
+
+    // Stdin was closed, so the rest of the idle stream fails
+    drop(idle_tx);
+    assert!(idle.await?.is_err(), "Expected an error, instead succeeded");
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+#![deny(unused_must_use)]
+
+mod helpers;
+
+use helpers::{client_tls_config, TestServer};
+
+use client::{Client, JobStartRequest, TerminalInput, TerminalSize};
+
+#[tokio::test]
+async fn test_attach() -> Result<(), Box<dyn std::error::Error>> {
+    let s = TestServer::new();
+
+    let mut client = Client::connect(format!("grpcs://{}", s.addr), client_tls_config(1)).await?;
+
+    let jobid = client
+        .start(JobStartRequest {
+            path: "sh".to_owned(),
+            args: vec![
+                "-c".to_owned(),
+                "read line; stty size; echo got $line".to_owned(),
+            ],
+            tty: Some(TerminalSize { rows: 24, cols: 80 }),
+            ..Default::default()
+        })
+        .await?;
+
+    let (input_tx, input_rx) = tokio::sync::mpsc::channel(2);
+    let (output_tx, mut output_rx) = tokio::sync::mpsc::channel(2);
+    client.attach(jobid, input_rx, output_tx).await?;
+
+    input_tx
+        .send(TerminalInput::Resize(TerminalSize {
+            rows: 30,
+            cols: 100,
+        }))
+        .await?;
+    input_tx
+        .send(TerminalInput::Data(b"hello\n".to_vec()))
+        .await?;
+
+    let mut output = Vec::new();
+    while let Some(data) = output_rx.recv().await {
+        output.extend(data?);
+    }
+    let output = String::from_utf8(output)?;
+    assert!(
+        output.contains("30 100\r\n"),
+        "Unexpected output {:?}",
+        output
+    );
+    assert!(
+        output.contains("got hello\r\n"),
+        "Unexpected output {:?}",
+        output
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_attach_without_terminal() -> Result<(), Box<dyn std::error::Error>> {
+    let s = TestServer::new();
+
+    let mut client = Client::connect(format!("grpcs://{}", s.addr), client_tls_config(1)).await?;
+
+    let jobid = client
+        .start(JobStartRequest {
+            path: "sleep".to_owned(),
+            args: vec!["infinity".to_owned()],
+            ..Default::default()
+        })
+        .await?;
+
+    let (_input_tx, input_rx) = tokio::sync::mpsc::channel(1);
+    let (output_tx, _output_rx) = tokio::sync::mpsc::channel(1);
+    let result = client.attach(jobid, input_rx, output_tx).await;
+    assert!(result.is_err(), "Expected an error, instead succeeded");
+
+    client.stop(jobid).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_attach_completed() -> Result<(), Box<dyn std::error::Error>> {
+    let s = TestServer::new();
+
+    let mut client = Client::connect(format!("grpcs://{}", s.addr), client_tls_config(1)).await?;
+
+    let jobid = client
+        .start(JobStartRequest {
+            path: "true".to_owned(),
+            tty: Some(TerminalSize { rows: 24, cols: 80 }),
+            ..Default::default()
+        })
+        .await?;
+    client.wait(jobid).await?;
+
+    let (_input_tx, input_rx) = tokio::sync::mpsc::channel(1);
+    let (output_tx, _output_rx) = tokio::sync::mpsc::channel(1);
+    let result = client.attach(jobid, input_rx, output_tx).await;
+    let err = result.expect_err("Expected an error, instead succeeded");
+    assert!(
+        format!("{:?}", err).contains("FailedPrecondition"),
+        "Unexpected error {:?}",
+        err
+    );
+
+    Ok(())
+}
@@ -0,0 +1,146 @@
+use std::io;
+
+/// Capability names, indexed by capability number
+const NAMES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// `_LINUX_CAPABILITY_VERSION_3`, with 64-bit capability sets
+const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Parse a capability name, e.g. `CAP_NET_BIND_SERVICE` or `net_bind_service`
+pub fn parse(name: &str) -> Result<u32, String> {
+    let upper = name.to_ascii_uppercase();
+    let full = if upper.starts_with("CAP_") {
+        upper
+    } else {
+        format!("CAP_{}", upper)
+    };
+    NAMES
+        .iter()
+        .position(|n| *n == full)
+        .map(|index| index as u32)
+        .ok_or_else(|| format!("Unknown capability {:?}", name))
+}
+
+/// Parse a list of capability names into a bit set
+pub fn parse_set(names: &[String]) -> Result<u64, String> {
+    names
+        .iter()
+        .try_fold(0, |set, name| Ok(set | 1 << parse(name)?))
+}
+
+/// Highest capability number supported by the kernel
+pub fn last_cap() -> Result<u32, String> {
+    std::fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .map_err(|e| format!("Reading cap_last_cap failed: {}", e))?
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid cap_last_cap: {}", e))
+}
+
+/// Drop all capabilities not in `keep` from the bounding set,
+/// so that exec cannot give them back. Requires `CAP_SETPCAP`.
+pub unsafe fn drop_bounding(keep: u64, last_cap: u32) -> io::Result<()> {
+    for cap in 0..=last_cap {
+        if keep & (1 << cap) == 0
+            && libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Set the effective, permitted, inheritable and ambient sets to `keep`.
+/// The capabilities must be in the permitted set already.
+pub unsafe fn restrict(keep: u64, last_cap: u32) -> io::Result<()> {
+    let mut header = CapUserHeader {
+        version: CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [
+        CapUserData {
+            effective: keep as u32,
+            permitted: keep as u32,
+            inheritable: keep as u32,
+        },
+        CapUserData {
+            effective: (keep >> 32) as u32,
+            permitted: (keep >> 32) as u32,
+            inheritable: (keep >> 32) as u32,
+        },
+    ];
+    if libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Ambient capabilities are kept over exec even without file capabilities
+    let clear_all = libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong;
+    if libc::prctl(libc::PR_CAP_AMBIENT, clear_all, 0, 0, 0) < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let raise = libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong;
+    for cap in 0..=last_cap {
+        if keep & (1 << cap) != 0
+            && libc::prctl(libc::PR_CAP_AMBIENT, raise, cap as libc::c_ulong, 0, 0) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
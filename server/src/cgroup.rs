@@ -0,0 +1,255 @@
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use common::JobId;
+
+/// `f_type` of a cgroup v2 filesystem, see `statfs(2)`
+const CGROUP2_SUPER_MAGIC: i64 = 0x6367_7270;
+
+/// How long to wait for the processes of a cgroup to freeze or thaw
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for killed processes to exit
+const KILL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Controllers the job cgroups are allowed to use
+const CONTROLLERS: &[&str] = &["cpu", "cpuset", "io", "memory", "pids"];
+
+/// Find where the cgroup v2 hierarchy is mounted, if anywhere
+pub fn detect_mount() -> Option<PathBuf> {
+    let mounts = std::fs::read_to_string("/proc/mounts").ok()?;
+    mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let mount_point = fields.nth(1)?;
+        if fields.next()? == "cgroup2" {
+            Some(PathBuf::from(mount_point))
+        } else {
+            None
+        }
+    })
+}
+
+/// Checks that the path is on a cgroup v2 filesystem
+fn is_cgroup2(path: &Path) -> io::Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut buf) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(buf.f_type as i64 == CGROUP2_SUPER_MAGIC)
+}
+
+/// The cgroup under which all job cgroups are created
+#[derive(Debug)]
+pub struct CgroupRoot {
+    path: PathBuf,
+}
+impl CgroupRoot {
+    /// Creates the root cgroup if required, and delegates all
+    /// available controllers to the job cgroups under it.
+    pub fn setup(path: PathBuf) -> io::Result<Self> {
+        let parent = path.parent().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "cgroup root has no parent")
+        })?;
+        if !is_cgroup2(parent)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a cgroup v2 filesystem", parent),
+            ));
+        }
+
+        if !path.exists() {
+            std::fs::create_dir(&path)?;
+        }
+
+        // Controllers must be enabled in the parent before they're usable here
+        let available = std::fs::read_to_string(parent.join("cgroup.controllers"))?;
+        for controller in available.split_whitespace() {
+            if CONTROLLERS.contains(&controller) {
+                let enable = format!("+{}", controller);
+                for dir in &[parent, path.as_path()] {
+                    if let Err(err) = std::fs::write(dir.join("cgroup.subtree_control"), &enable) {
+                        log::warn!("Could not enable cgroup controller {}: {}", controller, err);
+                    }
+                }
+            }
+        }
+
+        Ok(Self { path })
+    }
+
+    /// Whether a controller is enabled for the job cgroups
+    pub fn has_controller(&self, name: &str) -> bool {
+        std::fs::read_to_string(self.path.join("cgroup.subtree_control"))
+            .map(|enabled| enabled.split_whitespace().any(|c| c == name))
+            .unwrap_or(false)
+    }
+
+    /// Read a control file of the root cgroup
+    pub fn read(&self, file: &str) -> io::Result<String> {
+        std::fs::read_to_string(self.path.join(file))
+    }
+
+    /// Creates a new cgroup for a job
+    pub fn create(&self, jobid: JobId) -> io::Result<Cgroup> {
+        let path = self.path.join(jobid.to_string());
+        std::fs::create_dir(&path)?;
+        Ok(Cgroup { path })
+    }
+}
+
+/// Cgroup of a single job
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+impl Cgroup {
+    /// Write a value to a control file
+    pub fn write(&self, file: &str, value: &str) -> io::Result<()> {
+        let path = self.path.join(file);
+        if !path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not supported (controller not enabled?)", file),
+            ));
+        }
+        std::fs::write(path, value)
+    }
+
+    /// Read a control file with a single value, e.g. `memory.peak`
+    pub fn read_value(&self, file: &str) -> io::Result<u64> {
+        let content = std::fs::read_to_string(self.path.join(file))?;
+        content
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {}", file)))
+    }
+
+    /// Read a control file
+    pub fn read(&self, file: &str) -> io::Result<String> {
+        std::fs::read_to_string(self.path.join(file))
+    }
+
+    /// Read a single value from a flat keyed file, e.g. `memory.events`
+    pub fn read_keyed(&self, file: &str, key: &str) -> io::Result<u64> {
+        let content = std::fs::read_to_string(self.path.join(file))?;
+        content
+            .lines()
+            .find_map(|line| {
+                let mut fields = line.split_whitespace();
+                if fields.next()? == key {
+                    fields.next()?.parse().ok()
+                } else {
+                    None
+                }
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is missing from {}", key, file),
+                )
+            })
+    }
+
+    /// Wait until a key of `cgroup.events` has the value, polling it until the timeout
+    async fn wait_event(&self, key: &str, value: u64, timeout: Duration) -> io::Result<()> {
+        let confirmed = async {
+            loop {
+                if self.read_keyed("cgroup.events", key)? == value {
+                    return Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(timeout, confirmed)
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} in cgroup.events did not change to {}", key, value),
+                ))
+            })
+    }
+
+    /// Freeze or thaw all processes in the cgroup, and wait until
+    /// `cgroup.events` reports that the change has taken effect.
+    /// If it doesn't, the previous value is restored.
+    pub async fn set_frozen(&self, frozen: bool) -> io::Result<()> {
+        let previous = self.read_value("cgroup.freeze")?;
+        let value = frozen as u64;
+        self.write("cgroup.freeze", &value.to_string())?;
+        // Processes in uninterruptible sleep are frozen only when they wake up
+        let result = self.wait_event("frozen", value, FREEZE_TIMEOUT).await;
+        if result.is_err() {
+            // Otherwise the change could still take effect later, after the error is reported
+            if let Err(err) = self.write("cgroup.freeze", &previous.to_string()) {
+                log::warn!("Could not restore cgroup.freeze: {}", err);
+            }
+        }
+        result
+    }
+
+    /// Send `SIGKILL` to all processes in the cgroup, including those created while killing.
+    /// Kernels before 5.14 don't have `cgroup.kill`, so the processes are killed one by one.
+    pub fn kill_all(&self) -> io::Result<()> {
+        let kill_file = self.path.join("cgroup.kill");
+        if kill_file.exists() {
+            return std::fs::write(kill_file, "1");
+        }
+        // Processes may fork while they're being killed, so repeat until no new ones appear
+        let mut killed = HashSet::new();
+        loop {
+            let procs = self.read("cgroup.procs")?;
+            let mut found_new = false;
+            for pid in procs.lines().filter_map(|line| line.parse().ok()) {
+                if killed.insert(pid) {
+                    unsafe { libc::kill(pid, libc::SIGKILL) };
+                    found_new = true;
+                }
+            }
+            if !found_new {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Wait until all processes have left the cgroup
+    pub async fn wait_empty(&self) -> io::Result<()> {
+        self.wait_event("populated", 0, KILL_TIMEOUT).await
+    }
+
+    /// Whether the processes in the cgroup are frozen
+    pub fn is_frozen(&self) -> bool {
+        self.read_keyed("cgroup.events", "frozen").unwrap_or(0) == 1
+    }
+
+    /// Path of `cgroup.procs`, for use in `pre_exec`
+    pub fn procs_path(&self) -> CString {
+        CString::new(self.path.join("cgroup.procs").as_os_str().as_bytes())
+            .expect("cgroup path contains a nul byte")
+    }
+
+    /// Removes the cgroup. Fails if it still has processes in it.
+    pub fn remove(&self) -> io::Result<()> {
+        std::fs::remove_dir(&self.path)
+    }
+}
+
+/// Moves the calling process into the cgroup.
+pub unsafe fn join_self(procs_path: &CString) -> io::Result<()> {
+    let fd = libc::open(procs_path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Writing zero means the writing process itself
+    let result = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+    libc::close(fd);
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -21,6 +21,10 @@ impl ClientName {
         Self(cn.to_owned())
     }
 
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
     /// Reads certificate info from a Tonic request
     pub fn from_request<T>(request: &tonic::Request<T>) -> Option<Self> {
         use std::borrow::Borrow;
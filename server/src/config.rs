@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::capabilities;
+use crate::rlimits;
+use crate::seccomp::{self, Profiles};
+use crate::userns;
+
+/// Server configuration, read from a TOML file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Used when the request doesn't specify a value
+    pub defaults: Defaults,
+    /// What clients are allowed to request
+    pub policy: Policy,
+    /// Bridged networking is disabled if not set
+    pub bridge: Option<BridgeConfig>,
+    /// User namespaces are disabled if not set
+    pub user_namespace: Option<UserNamespaceConfig>,
+    /// Seccomp filtering is disabled if not set
+    pub seccomp: Option<SeccompConfig>,
+    /// Maximum rlimits of jobs by resource name, e.g. `RLIMIT_NOFILE`.
+    /// Applied to all jobs, and requests can only lower them.
+    pub rlimits: HashMap<String, u64>,
+    /// Users and groups each client may run jobs as, by client certificate CN
+    pub users: HashMap<String, UserPolicy>,
+    /// Compiled profiles of `seccomp.profiles`
+    #[serde(skip)]
+    pub seccomp_profiles: Profiles,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Defaults {
+    /// Maximum number of processes in a job, zero for unlimited
+    pub max_processes: u64,
+    /// Root filesystem of jobs. The host root is used if not set.
+    pub rootfs: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// Allow jobs to use the network namespace of the server
+    pub allow_host_network: bool,
+    /// Capabilities jobs may keep, e.g. `CAP_NET_BIND_SERVICE`.
+    /// All other capabilities are always dropped.
+    pub allowed_capabilities: Vec<String>,
+    /// Directories under which jobs may bind-mount files and directories
+    pub mount_sources: Vec<PathBuf>,
+    /// Directories under which root filesystems requested by jobs must be
+    pub rootfs: Vec<PathBuf>,
+    /// Allow jobs to request a writable root filesystem
+    pub allow_writable_rootfs: bool,
+    /// Directories under which OCI bundles requested by jobs must be
+    pub bundles: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserPolicy {
+    /// User names or numeric uids
+    pub users: Vec<String>,
+    /// Group names or numeric gids, for both primary and supplementary groups
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BridgeConfig {
+    /// Name of the bridge interface, created if it doesn't exist
+    pub name: String,
+    /// Jobs get addresses from this subnet, e.g. `10.88.0.0/16`.
+    /// The first address is assigned to the bridge.
+    pub subnet: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserNamespaceConfig {
+    /// Host ID ranges that may be mapped into jobs, like `/etc/subuid`
+    pub ranges: Vec<IdRange>,
+    /// Number of IDs mapped for each job
+    pub ids_per_job: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SeccompConfig {
+    /// Profile file in seccompiler JSON format, relative to the config file
+    pub profiles: PathBuf,
+    /// Profile of jobs that don't select one, no filter if not set
+    #[serde(default)]
+    pub default_profile: Option<String>,
+}
+
+impl Config {
+    /// Built-in defaults are used if the file doesn't exist
+    pub async fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = match tokio::fs::read_to_string(path).await {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::info!("Config file {:?} not found, using defaults", path);
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(format!("Reading config file {:?} failed: {}", path, e).into()),
+        };
+        let mut config: Self = toml::from_str(&text)?;
+        capabilities::parse_set(&config.policy.allowed_capabilities)?;
+        rlimits::validate(&config.rlimits)?;
+        if let Some(user_namespace) = &config.user_namespace {
+            userns::validate(user_namespace)?;
+        }
+        if let Some(seccomp) = &config.seccomp {
+            let dir = path.parent().unwrap_or_else(|| Path::new("."));
+            config.seccomp_profiles = seccomp::load(&dir.join(&seccomp.profiles))?;
+            if let Some(name) = &seccomp.default_profile {
+                if !config.seccomp_profiles.contains_key(name) {
+                    return Err(format!("Default seccomp profile {:?} does not exist", name).into());
+                }
+            }
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::BTreeSet;
+
+use common::CpuSet;
+
+use crate::cgroup::{Cgroup, CgroupRoot};
+
+/// Largest CPU or memory node number accepted, the kernel supports at most 8192 CPUs.
+/// Checked before expanding ranges, so that huge ranges can't exhaust memory.
+const MAX_ID: u32 = 8191;
+
+/// Parse a list in the kernel cpuset format, e.g. "0-3,8"
+pub fn parse_list(list: &str) -> Result<BTreeSet<u32>, String> {
+    let invalid = || format!("Invalid CPU or memory node list {:?}", list);
+
+    let mut result = BTreeSet::new();
+    for part in list.trim().split(',').filter(|p| !p.is_empty()) {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first, last),
+            None => (part, part),
+        };
+        let first: u32 = first.parse().map_err(|_| invalid())?;
+        let last: u32 = last.parse().map_err(|_| invalid())?;
+        if first > last || last > MAX_ID {
+            return Err(invalid());
+        }
+        result.extend(first..=last);
+    }
+    Ok(result)
+}
+
+/// Checks that the requested set is a subset of what's available to the jobs
+fn validate(
+    root: &CgroupRoot,
+    kind: &str,
+    requested: &str,
+    effective_file: &str,
+) -> Result<(), String> {
+    let requested = parse_list(requested)?;
+    let available = root
+        .read(effective_file)
+        .map_err(|e| format!("Reading {} failed: {}", effective_file, e))?;
+    let available = parse_list(&available)?;
+    let missing: Vec<String> = requested
+        .difference(&available)
+        .map(|n| n.to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("Unavailable {}: {}", kind, missing.join(",")));
+    }
+    Ok(())
+}
+
+/// Pin the job to the CPUs and memory nodes of the request
+pub fn apply(root: &CgroupRoot, cgroup: &Cgroup, cpuset: &CpuSet) -> Result<(), String> {
+    if !cpuset.cpus.is_empty() {
+        validate(root, "CPUs", &cpuset.cpus, "cpuset.cpus.effective")?;
+        cgroup
+            .write("cpuset.cpus", &cpuset.cpus)
+            .map_err(|e| format!("Setting CPU set failed: {}", e))?;
+    }
+    if !cpuset.mems.is_empty() {
+        validate(root, "memory nodes", &cpuset.mems, "cpuset.mems.effective")?;
+        cgroup
+            .write("cpuset.mems", &cpuset.mems)
+            .map_err(|e| format!("Setting memory nodes failed: {}", e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_list() {
+        let parsed = parse_list("0-3,8\n").unwrap();
+        assert_eq!(parsed.into_iter().collect::<Vec<_>>(), vec![0, 1, 2, 3, 8]);
+        assert!(parse_list("").unwrap().is_empty());
+        assert!(parse_list("3-1").is_err());
+        assert!(parse_list("a").is_err());
+    }
+
+    #[test]
+    fn test_parse_list_huge_range() {
+        assert!(parse_list("0-4294967295").is_err());
+        assert!(parse_list("4294967295").is_err());
+        assert!(parse_list(&format!("0-{}", MAX_ID)).is_ok());
+    }
+}
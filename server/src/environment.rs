@@ -0,0 +1,59 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use common::{JobId, JobStartRequest};
+
+/// Used when the job doesn't inherit the server environment, unless the job sets it
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Environment variables and working directory of a job process
+#[derive(Debug)]
+pub struct Environment {
+    pub vars: Vec<(String, String)>,
+    /// Absolute path in the job filesystem. If not set, the working directory of the
+    /// server is kept, or the root directory if the job has its own root filesystem.
+    pub cwd: Option<String>,
+}
+impl Environment {
+    /// Variables of the request override those inherited from the server,
+    /// and `JOB_ID` is always set to the id of the job.
+    pub fn new(jobid: JobId, req: &JobStartRequest) -> Result<Self, String> {
+        let mut vars = BTreeMap::new();
+        if req.inherit_env {
+            // Variables that aren't valid UTF-8 are skipped
+            vars.extend(std::env::vars_os().filter_map(|(key, value)| {
+                Some((key.into_string().ok()?, value.into_string().ok()?))
+            }));
+        }
+        for (key, value) in &req.env {
+            if key.is_empty() || key.contains(&['=', '\0'][..]) {
+                return Err(format!("Invalid environment variable name {:?}", key));
+            }
+            if value.contains('\0') {
+                return Err(format!("Environment variable {} contains a nul byte", key));
+            }
+            vars.insert(key.clone(), value.clone());
+        }
+        if !req.inherit_env {
+            vars.entry("PATH".to_owned())
+                .or_insert_with(|| DEFAULT_PATH.to_owned());
+        }
+        vars.insert("JOB_ID".to_owned(), jobid.to_string());
+
+        let cwd = if req.cwd.is_empty() {
+            None
+        } else if Path::new(&req.cwd).is_absolute() {
+            Some(req.cwd.clone())
+        } else {
+            return Err(format!(
+                "Working directory {:?} is not an absolute path",
+                req.cwd
+            ));
+        };
+
+        Ok(Self {
+            vars: vars.into_iter().collect(),
+            cwd,
+        })
+    }
+}
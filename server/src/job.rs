@@ -1,84 +1,356 @@
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::net::Ipv4Addr;
 use std::os::unix::process::ExitStatusExt;
 use std::process::{ExitStatus, Stdio};
 use std::sync::Arc;
-use tokio::process::Command;
-use tokio::sync::{Notify, OnceCell};
+use tokio::process::{ChildStdin, Command};
+use tokio::sync::{Mutex, Notify, OnceCell};
 
-use common::job_status::Completed;
+use common::job_status::{Completed, Reason};
 use common::output_event::Stream as OutputStream;
 use common::*;
 
+use crate::cgroup::{Cgroup, CgroupRoot};
 use crate::client_cert::ClientName;
+use crate::config::Config;
+use crate::cpuset;
+use crate::environment::Environment;
+use crate::limits;
+use crate::network::Attachment;
 use crate::output_stream::OutputHandler;
+use crate::pty::Terminal;
+use crate::sandbox::Sandbox;
+use crate::tmpfs::Scratch;
+use crate::usage;
+use crate::userns::IdMapping;
 
-/// Map status of a completed process to `JobStatus`
-fn completed_status(status: ExitStatus) -> JobStatus {
-    if let Some(value) = status.code() {
-        JobStatus {
-            completed: Some(Completed::StatusCode(value)),
-        }
+/// Map status of a completed process to `JobStatus`.
+/// The cgroup is inspected to find out why the process was killed.
+/// `stopped` is set if the server killed the job for `Stop`.
+fn completed_status(
+    status: ExitStatus,
+    cgroup: Option<&Cgroup>,
+    rusage: Option<&libc::rusage>,
+    stopped: bool,
+) -> JobStatus {
+    let oom_killed = cgroup
+        .map(|cgroup| cgroup.read_keyed("memory.events", "oom_kill").unwrap_or(0) > 0)
+        .unwrap_or(false);
+    let completed = if let Some(value) = status.code() {
+        Completed::StatusCode(value)
     } else if let Some(value) = status.signal() {
-        JobStatus {
-            completed: Some(Completed::Signal(value)),
+        // The shim reports SIGKILL at the CPU time limit as SIGXCPU,
+        // which is wrong if the job was killed for another reason
+        if value == libc::SIGXCPU && (stopped || oom_killed) {
+            Completed::Signal(libc::SIGKILL)
+        } else {
+            Completed::Signal(value)
         }
     } else {
         panic!("Unknown process exit state")
+    };
+
+    let mut reason = Reason::Unknown;
+    if let Completed::Signal(signal) = completed {
+        reason = match signal {
+            libc::SIGSYS => Reason::SeccompViolation,
+            libc::SIGXCPU => Reason::CpuTimeLimit,
+            libc::SIGXFSZ => Reason::FileSizeLimit,
+            _ if oom_killed => Reason::OutOfMemory,
+            _ => Reason::Unknown,
+        };
+    }
+
+    let mut status = JobStatus {
+        completed: Some(completed),
+        reason: reason as i32,
+        usage: usage::collect(cgroup, rusage),
+        ..Default::default()
+    };
+    if let Some(cgroup) = cgroup {
+        read_events(cgroup, &mut status);
+    }
+    status
+}
+
+/// Kill all processes of a job: the whole cgroup if the job has one,
+/// otherwise the process group of the job process.
+/// The job process must not have been reaped yet, so that its pid is not reused.
+fn kill_tree(pid: libc::pid_t, cgroup: Option<&Cgroup>) {
+    if let Some(cgroup) = cgroup {
+        match cgroup.kill_all() {
+            Ok(()) => return,
+            Err(err) => log::warn!("Could not kill job cgroup: {}", err),
+        }
     }
+    if unsafe { libc::kill(-pid, libc::SIGKILL) } < 0 {
+        log::warn!("Could not kill job: {}", std::io::Error::last_os_error());
+    }
+}
+
+/// Update status with events from the job cgroup
+fn read_events(cgroup: &Cgroup, status: &mut JobStatus) {
+    status.process_limit_reached = cgroup.read_keyed("pids.events", "max").unwrap_or(0) > 0;
+}
+
+/// Host resources reserved for a job before it's spawned,
+/// released when the job completes
+#[derive(Debug, Default)]
+pub struct Reserved {
+    /// Network namespace in bridge network mode
+    pub network: Option<Attachment>,
+    /// Host IDs of the user namespace
+    pub id_mapping: Option<IdMapping>,
+    /// Tmpfs mounts, in the order of the request
+    pub tmpfs: Vec<Scratch>,
 }
 
+/// Write end of the stdin pipe of a job, `None` after it has been closed.
+/// Locked while writing a chunk, so that the data of a chunk isn't interleaved with other writes.
+pub type StdinPipe = Arc<Mutex<Option<ChildStdin>>>;
+
+/// Master side of the pseudo-terminal of a job, `None` after the output of the job has ended
+pub type TerminalSlot = Arc<std::sync::Mutex<Option<Terminal>>>;
+
 /// A single running job, i.e. a process
 pub struct Job {
     pub owner: ClientName,
-    status: Arc<OnceCell<ExitStatus>>,
+    cgroup: Option<Arc<Cgroup>>,
+    /// CPUs reserved for this job only
+    exclusive_cpus: Option<BTreeSet<u32>>,
+    /// Address in the job bridge network
+    address: Option<Ipv4Addr>,
+    /// User namespace ID mapping
+    id_mapping: Option<common::IdMapping>,
+    status: Arc<OnceCell<JobStatus>>,
+    /// Notified after the status has been set
+    completed: Arc<Notify>,
     kill_request: Arc<Notify>,
+    /// Set if the job was started with stdin
+    stdin: Option<StdinPipe>,
+    /// Set if the job was started with a pseudo-terminal
+    terminal: Option<TerminalSlot>,
     pub stdout: Arc<OutputHandler>,
     pub stderr: Arc<OutputHandler>,
 }
 impl Job {
-    pub fn spawn(owner: ClientName, req: JobStartRequest) -> Result<Self, String> {
-        let mut cmd = Command::new(req.path);
+    pub fn spawn(
+        jobid: JobId,
+        owner: ClientName,
+        req: JobStartRequest,
+        config: &Config,
+        cgroups: Option<&CgroupRoot>,
+        reserved: Reserved,
+        environment: Environment,
+    ) -> Result<Self, String> {
+        let terminal = req
+            .tty
+            .as_ref()
+            .map(Terminal::open)
+            .transpose()
+            .map_err(|e| format!("Opening pseudo-terminal failed: {}", e))?;
 
-        cmd.args(req.args);
+        let exclusive_cpus = match &req.cpuset {
+            Some(set) if set.exclusive => Some(cpuset::parse_list(&set.cpus)?),
+            _ => None,
+        };
 
-        cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::piped());
+        let mut cmd = Command::new(&req.path);
 
-        // TODO: use pre_exec to configure cgroups and namespaces
+        cmd.args(&req.args);
 
-        let mut child = cmd.spawn().map_err(|e| format!("{:?}", e))?;
+        // Only what the environment includes, which may be inherited from the server
+        cmd.env_clear();
+        cmd.envs(environment.vars.iter().cloned());
 
-        let stdout = OutputHandler::setup(OutputStream::Stdout, child.stdout.take().unwrap());
-        let stderr = OutputHandler::setup(OutputStream::Stderr, child.stderr.take().unwrap());
+        if let Some((_, slave)) = &terminal {
+            // The sandbox makes it the controlling terminal of a new session
+            let stdio = || slave.try_clone().map_err(|e| format!("{:?}", e));
+            cmd.stdin(stdio()?);
+            cmd.stdout(stdio()?);
+            cmd.stderr(stdio()?);
+        } else {
+            // Leads a process group, so that the job can be killed without a cgroup too
+            cmd.process_group(0);
+
+            cmd.stdin(if req.stdin {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            });
+            cmd.stdout(Stdio::piped());
+            cmd.stderr(Stdio::piped());
+        }
+
+        // Created after the steps that cannot undo it, so that their errors leave nothing behind
+        let cgroup = match cgroups {
+            Some(root) => {
+                let cgroup = root
+                    .create(jobid)
+                    .map_err(|e| format!("Creating cgroup failed: {}", e))?;
+                if let Err(err) = limits::apply(root, &cgroup, &req, &config.defaults) {
+                    let _ = cgroup.remove();
+                    return Err(err);
+                }
+                Some(Arc::new(cgroup))
+            }
+            None if limits::requires_cgroup(&req) => {
+                return Err("Resource limits require cgroup v2, which is not available".to_owned())
+            }
+            None => None,
+        };
+
+        let sandbox = match Sandbox::new(
+            jobid,
+            &req,
+            config,
+            cgroup.as_deref(),
+            &reserved,
+            environment.cwd.as_deref(),
+        ) {
+            Ok(sandbox) => sandbox,
+            Err(err) => {
+                if let Some(cgroup) = &cgroup {
+                    let _ = cgroup.remove();
+                }
+                return Err(err);
+            }
+        };
+        unsafe {
+            cmd.pre_exec(move || sandbox.enter());
+        }
+
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                if let Some(cgroup) = &cgroup {
+                    let _ = cgroup.remove();
+                }
+                return Err(format!("{:?}", err));
+            }
+        };
+
+        let stdin = child
+            .stdin
+            .take()
+            .map(|pipe| Arc::new(Mutex::new(Some(pipe))));
+        // Everything written to the terminal is output to stdout
+        let terminal = terminal.map(|(terminal, _)| terminal);
+        let (stdout, stderr) = match &terminal {
+            Some(terminal) => (
+                OutputHandler::setup(OutputStream::Stdout, terminal.reader()),
+                OutputHandler::setup(OutputStream::Stderr, tokio::io::empty()),
+            ),
+            None => (
+                OutputHandler::setup(OutputStream::Stdout, child.stdout.take().unwrap()),
+                OutputHandler::setup(OutputStream::Stderr, child.stderr.take().unwrap()),
+            ),
+        };
 
         let status = Arc::new(OnceCell::new());
+        let completed = Arc::new(Notify::new());
         let kill_request = Arc::new(Notify::new());
 
+        let pid = child.id().expect("Process has no pid") as libc::pid_t;
+
         // State management task
         let status_handle = status.clone();
+        let completed_handle = completed.clone();
         let kill_requested = kill_request.clone();
+        let job_cgroup = cgroup.clone();
+        let address = reserved.network.as_ref().map(|n| n.address);
+        let id_mapping = reserved.id_mapping.as_ref().map(IdMapping::to_status);
+        let stdin_handle = stdin.clone();
+        let terminal = terminal.map(|terminal| Arc::new(std::sync::Mutex::new(Some(terminal))));
+        let terminal_handle = terminal.clone();
+        let stdout_handle = stdout.clone();
         tokio::spawn(async move {
-            tokio::select! {
-                wait_result = child.wait() => {
-                    // Process completed
-                    log::debug!("Process completed {:?}", wait_result);
-                    let _ = status_handle.set(wait_result.expect("Unknown process exit state"));
-                },
+            // Wait without reaping first, to get the resource usage of the process
+            let exited = usage::wait_exited(pid);
+            tokio::pin!(exited);
+            let mut stopped = false;
+            let rusage = tokio::select! {
+                rusage = &mut exited => rusage,
                 _ = kill_requested.notified() => {
-                    // Kill the process
+                    // Kill the process and all of its descendants
                     log::debug!("Killing job");
-                    child.kill().await.expect("kill failed");
-                    let wait_result = child.wait().await.expect("wait failed");
-                    // Process completed
-                    log::debug!("Job killed {:?}", wait_result);
-                    let _ = status_handle.set(wait_result);
+                    stopped = true;
+                    kill_tree(pid, job_cgroup.as_deref());
+                    exited.await
+                }
+            };
+            let rusage = rusage
+                .map_err(|err| log::warn!("Could not read job resource usage: {}", err))
+                .ok();
+            let wait_result = if rusage.is_none() && !stopped {
+                // Waiting without reaping failed, so kill requests are handled here instead
+                tokio::select! {
+                    result = child.wait() => result,
+                    _ = kill_requested.notified() => {
+                        log::debug!("Killing job");
+                        stopped = true;
+                        kill_tree(pid, job_cgroup.as_deref());
+                        child.wait().await
+                    }
                 }
+            } else {
+                child.wait().await
+            };
+            let wait_result = wait_result.expect("wait failed");
+            // Process completed
+            log::debug!("Process completed {:?}", wait_result);
+
+            // Processes left behind, e.g. in the host PID namespace, are killed with the job
+            if let Some(cgroup) = &job_cgroup {
+                let emptied = match cgroup.kill_all() {
+                    Ok(()) => cgroup.wait_empty().await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = emptied {
+                    log::warn!("Could not kill remaining job processes: {}", err);
+                }
+            }
+
+            let mut status =
+                completed_status(wait_result, job_cgroup.as_deref(), rusage.as_ref(), stopped);
+            // Read before the tmpfs mounts are removed with the reservations
+            status.tmpfs = reserved.tmpfs.iter().map(Scratch::usage).collect();
+            let _ = status_handle.set(status);
+            completed_handle.notify_waiters();
+
+            drop(reserved);
+
+            if let Some(cgroup) = job_cgroup {
+                if let Err(err) = cgroup.remove() {
+                    log::warn!("Could not remove job cgroup: {}", err);
+                }
+            }
+
+            // Last, as a write blocked on a full pipe holds it until the job processes are gone
+            if let Some(stdin) = stdin_handle {
+                *stdin.lock().await = None;
+            }
+
+            // The output ends when all processes holding the terminal are gone,
+            // which may be later than the job if some of them escaped it
+            if let Some(terminal) = terminal_handle {
+                stdout_handle.wait_completed().await;
+                terminal.lock().unwrap().take();
             }
         });
 
         Ok(Self {
             owner,
+            cgroup,
+            exclusive_cpus,
+            address,
+            id_mapping,
             status,
+            completed,
             kill_request,
+            stdin,
+            terminal,
             stdout,
             stderr,
         })
@@ -86,13 +358,89 @@ impl Job {
 
     /// Start an asynchronous kill operation
     pub fn start_kill(&mut self) {
-        self.kill_request.notify_waiters();
+        // Stored if the state task isn't waiting yet
+        self.kill_request.notify_one();
+    }
+
+    /// Resolves when the job has completed and all of its processes are gone.
+    /// Doesn't borrow the job, so that it can be awaited without holding the job lock.
+    pub fn completion(&self) -> impl Future<Output = ()> + Send + 'static {
+        let status = self.status.clone();
+        let completed = self.completed.clone();
+        async move {
+            loop {
+                // Created before checking, so that the notification can't be missed
+                let notified = completed.notified();
+                if status.initialized() {
+                    return;
+                }
+                notified.await;
+            }
+        }
+    }
+
+    /// Stdin of the job, for writing to it without holding the job
+    pub fn stdin(&self) -> Result<StdinPipe, String> {
+        self.stdin
+            .clone()
+            .ok_or_else(|| "Job was started without stdin".to_owned())
+    }
+
+    /// Pseudo-terminal of the job, for attaching to it
+    pub fn terminal(&self) -> Result<Terminal, String> {
+        let terminal = self
+            .terminal
+            .as_ref()
+            .ok_or_else(|| "Job was started without a terminal".to_owned())?;
+        if !self.is_running() {
+            return Err("Job has already completed".to_owned());
+        }
+        terminal
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| "Job has already completed".to_owned())
+    }
+
+    /// Cgroup to freeze for pausing the job, only available while it's running
+    pub fn freezer(&self) -> Result<Arc<Cgroup>, String> {
+        if !self.is_running() {
+            return Err("Job has already completed".to_owned());
+        }
+        self.cgroup
+            .clone()
+            .ok_or_else(|| "Pausing jobs requires cgroup v2, which is not available".to_owned())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.status.get().is_none()
+    }
+
+    /// Whether this job has reserved any of the CPUs
+    pub fn reserves_any(&self, cpus: &BTreeSet<u32>) -> bool {
+        match &self.exclusive_cpus {
+            Some(reserved) => self.is_running() && !reserved.is_disjoint(cpus),
+            None => false,
+        }
     }
 
     pub fn status(&mut self) -> JobStatus {
-        match self.status.get() {
-            Some(status) => completed_status(*status),
-            None => JobStatus { completed: None },
+        let mut status = match self.status.get() {
+            Some(status) => status.clone(),
+            None => {
+                let mut status = JobStatus::default();
+                if let Some(cgroup) = &self.cgroup {
+                    read_events(cgroup, &mut status);
+                    status.usage = usage::collect(Some(cgroup), None);
+                    status.paused = cgroup.is_frozen();
+                }
+                status
+            }
+        };
+        if let Some(address) = self.address {
+            status.ip_address = address.to_string();
         }
+        status.id_mapping = self.id_mapping.clone();
+        status
     }
 }
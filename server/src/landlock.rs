@@ -0,0 +1,166 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use common::PathRule;
+
+/// `LANDLOCK_CREATE_RULESET_VERSION`
+const CREATE_RULESET_VERSION: u32 = 1;
+/// `LANDLOCK_RULE_PATH_BENEATH`
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_EXECUTE: u64 = 1 << 0;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+/// Everything up to `LANDLOCK_ACCESS_FS_MAKE_SYM`, supported since the first ABI version
+const ACCESS_ABI_1: u64 = (1 << 13) - 1;
+const ACCESS_REFER: u64 = 1 << 13;
+const ACCESS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_IOCTL_DEV: u64 = 1 << 15;
+
+/// Rights that apply to files, as opposed to directories
+const FILE_ACCESS: u64 =
+    ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE | ACCESS_TRUNCATE | ACCESS_IOCTL_DEV;
+const READ_ACCESS: u64 = ACCESS_EXECUTE | ACCESS_READ_FILE | ACCESS_READ_DIR;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+static ABI_VERSION: OnceLock<Option<u32>> = OnceLock::new();
+
+/// Landlock ABI version of the kernel, or `None` if Landlock is not available.
+/// Detected on the first call.
+pub fn abi_version() -> Option<u32> {
+    *ABI_VERSION.get_or_init(|| {
+        let version = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if version > 0 {
+            Some(version as u32)
+        } else {
+            None
+        }
+    })
+}
+
+/// Filesystem access rights that the ABI version can restrict
+fn handled_access(abi: u32) -> u64 {
+    let mut access = ACCESS_ABI_1;
+    if abi >= 2 {
+        access |= ACCESS_REFER;
+    }
+    if abi >= 3 {
+        access |= ACCESS_TRUNCATE;
+    }
+    if abi >= 5 {
+        access |= ACCESS_IOCTL_DEV;
+    }
+    access
+}
+
+/// Path rules prepared for `pre_exec`
+pub struct Ruleset {
+    handled: u64,
+    /// Paths in the job filesystem, and the access allowed beneath them
+    rules: Vec<(CString, u64)>,
+}
+impl Ruleset {
+    /// Paths are checked under the job root filesystem, if the job has one
+    pub fn new(rules: &[PathRule], rootfs: Option<&Path>) -> Result<Self, String> {
+        let abi = abi_version().ok_or_else(|| "Landlock is not supported".to_owned())?;
+        let handled = handled_access(abi);
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let path = Path::new(&rule.path);
+                if !path.is_absolute() {
+                    return Err(format!("Rule path {:?} is not an absolute path", path));
+                }
+                let host_path = match rootfs {
+                    Some(root) => root.join(path.strip_prefix("/").unwrap()),
+                    None => path.to_owned(),
+                };
+                if !host_path.exists() {
+                    return Err(format!("Rule path {:?} does not exist", path));
+                }
+                let access = if rule.writable { handled } else { READ_ACCESS };
+                let c_path = CString::new(path.as_os_str().as_bytes())
+                    .map_err(|_| format!("Rule path {:?} contains a nul byte", path))?;
+                Ok((c_path, access))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { handled, rules })
+    }
+
+    /// Restrict the calling thread to the rules. Requires `no_new_privs`.
+    /// Paths are resolved in the current mount namespace.
+    pub unsafe fn enforce(&self) -> io::Result<()> {
+        let attr = RulesetAttr {
+            handled_access_fs: self.handled,
+        };
+        let ruleset = libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        ) as libc::c_int;
+        if ruleset < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let result = self.add_rules(ruleset).and_then(|_| {
+            if libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+        libc::close(ruleset);
+        result
+    }
+
+    unsafe fn add_rules(&self, ruleset: libc::c_int) -> io::Result<()> {
+        for (path, access) in &self.rules {
+            let fd = libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut stat: libc::stat = std::mem::zeroed();
+            let mut allowed = *access;
+            if libc::fstat(fd, &mut stat) == 0 && stat.st_mode & libc::S_IFMT != libc::S_IFDIR {
+                // Directory rights are invalid for files
+                allowed &= FILE_ACCESS;
+            }
+            let attr = PathBeneathAttr {
+                allowed_access: allowed & self.handled,
+                parent_fd: fd,
+            };
+            let result = libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset,
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            );
+            libc::close(fd);
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
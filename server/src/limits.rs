@@ -0,0 +1,131 @@
+use std::io;
+
+use common::*;
+
+use crate::cgroup::{Cgroup, CgroupRoot};
+use crate::config::Defaults;
+use crate::cpuset;
+
+/// Look up `major:minor` numbers of a block device from `/proc/partitions`
+fn device_number(name: &str) -> io::Result<Option<String>> {
+    let name = name.strip_prefix("/dev/").unwrap_or(name);
+    let partitions = std::fs::read_to_string("/proc/partitions")?;
+    Ok(partitions.lines().skip(2).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [major, minor, _blocks, dev_name] if *dev_name == name => {
+                Some(format!("{}:{}", major, minor))
+            }
+            _ => None,
+        }
+    }))
+}
+
+/// Look up the name of a block device from `/proc/partitions`
+pub fn device_name(major: u64, minor: u64) -> io::Result<Option<String>> {
+    let partitions = std::fs::read_to_string("/proc/partitions")?;
+    Ok(partitions.lines().skip(2).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [ma, mi, _blocks, dev_name] if *ma == major.to_string() && *mi == minor.to_string() => {
+                Some((*dev_name).to_owned())
+            }
+            _ => None,
+        }
+    }))
+}
+
+/// Format `io.max` lines, one per device.
+/// Devices are resolved here, so that unknown ones are reported as errors.
+fn io_max_lines(limits: &[IoLimit]) -> Result<Vec<String>, String> {
+    /// Zero means unlimited
+    fn value(v: u64) -> String {
+        if v == 0 {
+            "max".to_owned()
+        } else {
+            v.to_string()
+        }
+    }
+
+    let mut lines = Vec::new();
+    for limit in limits {
+        let device = device_number(&limit.device)
+            .map_err(|e| format!("Reading /proc/partitions failed: {}", e))?
+            .ok_or_else(|| format!("No such block device: {:?}", limit.device))?;
+        lines.push(format!(
+            "{} rbps={} wbps={} riops={} wiops={}",
+            device,
+            value(limit.read_bps),
+            value(limit.write_bps),
+            value(limit.read_iops),
+            value(limit.write_iops),
+        ));
+    }
+    Ok(lines)
+}
+
+/// Write resource limits from the request to the job cgroup
+pub fn apply(
+    root: &CgroupRoot,
+    cgroup: &Cgroup,
+    req: &JobStartRequest,
+    defaults: &Defaults,
+) -> Result<(), String> {
+    if let Some(cpu) = &req.cpu {
+        let period = if cpu.period_us == 0 {
+            CpuLimit::DEFAULT_PERIOD_US
+        } else {
+            cpu.period_us
+        };
+        if !(1000..=1_000_000).contains(&period) {
+            return Err("CPU period must be between 1ms and 1s".to_owned());
+        }
+        if cpu.quota_us < 1000 {
+            return Err("CPU quota must be at least 1ms".to_owned());
+        }
+        cgroup
+            .write("cpu.max", &format!("{} {}", cpu.quota_us, period))
+            .map_err(|e| format!("Setting CPU limit failed: {}", e))?;
+    }
+    if req.memory_bytes != 0 {
+        let set_memory = || -> io::Result<()> {
+            cgroup.write("memory.max", &req.memory_bytes.to_string())?;
+            // Start reclaiming memory before the hard limit is reached
+            cgroup.write("memory.high", &(req.memory_bytes / 4 * 3).to_string())?;
+            // Kill the whole job instead of a single process when out of memory
+            cgroup.write("memory.oom.group", "1")
+        };
+        set_memory().map_err(|e| format!("Setting memory limit failed: {}", e))?;
+    }
+    if !req.io.is_empty() {
+        // The kernel accepts only a single device per write
+        for line in io_max_lines(&req.io)? {
+            cgroup
+                .write("io.max", &line)
+                .map_err(|e| format!("Setting IO limit failed: {}", e))?;
+        }
+    }
+    if let Some(set) = &req.cpuset {
+        cpuset::apply(root, cgroup, set)?;
+    }
+    let max_processes = if req.max_processes != 0 {
+        req.max_processes
+    } else {
+        defaults.max_processes
+    };
+    if max_processes != 0 {
+        cgroup
+            .write("pids.max", &max_processes.to_string())
+            .map_err(|e| format!("Setting process limit failed: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Whether the request needs a cgroup to work
+pub fn requires_cgroup(req: &JobStartRequest) -> bool {
+    req.cpu.is_some()
+        || req.memory_bytes != 0
+        || !req.io.is_empty()
+        || req.max_processes != 0
+        || req.cpuset.is_some()
+}
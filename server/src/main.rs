@@ -1,4 +1,6 @@
 #![deny(unused_must_use)]
+// Status is the error type of all tonic handlers
+#![allow(clippy::result_large_err)]
 
 use std::net::SocketAddr;
 use std::path::PathBuf;
@@ -8,12 +10,34 @@ use clap::{AppSettings, Clap};
 use common::t_service_server::TServiceServer;
 use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
 
+mod capabilities;
+mod cgroup;
 mod client_cert;
+mod config;
+mod cpuset;
+mod environment;
 mod job;
+mod landlock;
+mod limits;
+mod mounts;
+mod network;
+mod oci;
 mod output_stream;
+mod pty;
+mod rlimits;
+mod sandbox;
+mod seccomp;
 mod service;
+mod tmpfs;
+mod usage;
+mod userns;
+mod users;
 
+use self::cgroup::CgroupRoot;
+use self::config::Config;
+use self::network::Bridge;
 use self::service::TServiceImpl;
+use self::userns::IdAllocator;
 
 #[derive(Clap)]
 #[clap(version, author)]
@@ -39,6 +63,13 @@ struct Opts {
         default_value = "certs/server/server.key"
     )]
     server_key: PathBuf,
+    /// Configuration file
+    #[clap(long = "config", env = "CONFIG", default_value = "config/server.toml")]
+    config: PathBuf,
+    /// Cgroup v2 directory under which job cgroups are created.
+    /// Defaults to `job-runner` under the cgroup v2 mount point.
+    #[clap(long = "cgroup-root", env = "CGROUP_ROOT")]
+    cgroup_root: Option<PathBuf>,
     /// The address to serve at
     #[clap(default_value = "127.0.0.1:8000")]
     bind: SocketAddr,
@@ -61,7 +92,48 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .identity(server_identity)
         .client_ca_root(client_ca_crt);
 
-    let service = TServiceImpl::new();
+    let cgroup_root = opts
+        .cgroup_root
+        .or_else(|| cgroup::detect_mount().map(|mount| mount.join("job-runner")));
+    let cgroups = match cgroup_root.map(CgroupRoot::setup) {
+        Some(Ok(root)) => Some(root),
+        Some(Err(err)) => {
+            log::warn!("Cgroups disabled, setup failed: {}", err);
+            None
+        }
+        None => {
+            log::warn!("Cgroups disabled, cgroup v2 is not mounted");
+            None
+        }
+    };
+
+    let mut config = Config::load(&opts.config).await?;
+
+    let has_pids = matches!(&cgroups, Some(c) if c.has_controller("pids"));
+    if config.defaults.max_processes != 0 && !has_pids {
+        log::warn!("Default max_processes ignored, pids controller is not available");
+        config.defaults.max_processes = 0;
+    }
+
+    let bridge = match &config.bridge {
+        Some(bridge_config) => match Bridge::setup(bridge_config).await {
+            Ok(bridge) => Some(bridge),
+            Err(err) => {
+                log::warn!("Bridged networking disabled, setup failed: {}", err);
+                None
+            }
+        },
+        None => None,
+    };
+
+    match landlock::abi_version() {
+        Some(version) => log::info!("Landlock ABI version {}", version),
+        None => log::warn!("Landlock path rules disabled, not supported by the kernel"),
+    }
+
+    let ids = config.user_namespace.as_ref().map(IdAllocator::new);
+
+    let service = TServiceImpl::new(config, cgroups, bridge, ids);
     let server = Server::builder()
         .tls_config(tls)?
         .add_service(TServiceServer::new(service));
@@ -0,0 +1,325 @@
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::ptr;
+use std::sync::OnceLock;
+
+use common::Mount;
+
+use crate::sandbox::decimal;
+use crate::tmpfs::Scratch;
+
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+const MOUNT_ATTR_NOSUID: u64 = 0x2;
+const MOUNT_ATTR_NODEV: u64 = 0x4;
+const MOUNT_ATTR_NOEXEC: u64 = 0x8;
+
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+const RESOLVE_NO_MAGICLINKS: u64 = 0x2;
+const RESOLVE_IN_ROOT: u64 = 0x10;
+
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+static HAS_MOUNT_SETATTR: OnceLock<bool> = OnceLock::new();
+
+/// Whether the kernel supports `mount_setattr`, which can change the flags of
+/// a mount and all of its submounts. Detected on the first call.
+fn has_mount_setattr() -> bool {
+    *HAS_MOUNT_SETATTR.get_or_init(|| {
+        // Fails with EBADF if supported
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_mount_setattr,
+                -1,
+                ptr::null::<libc::c_char>(),
+                0,
+                ptr::null::<MountAttr>(),
+                0,
+            )
+        };
+        !(result < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ENOSYS))
+    })
+}
+
+/// Source of a mount, opened when the request is validated
+pub struct Source {
+    /// Path of the opened file, with symlinks and `..` resolved
+    pub path: PathBuf,
+    /// Device and inode of the file, so that it cannot be replaced before mounting
+    dev: u64,
+    ino: u64,
+}
+
+/// Open the source of a mount, and resolve its path from the opened file,
+/// so that symlinks and `..` cannot be used to escape the allowed prefixes
+pub fn resolve_source(source: &str) -> Result<Source, String> {
+    let error = |e: io::Error| format!("Mount source {:?}: {}", source, e);
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH)
+        .open(source)
+        .map_err(error)?;
+    let path = fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())).map_err(error)?;
+    let metadata = file.metadata().map_err(error)?;
+    Ok(Source {
+        path,
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+    })
+}
+
+/// Open a path with `O_PATH` as if `root` was the root directory,
+/// so that `..` and symlinks cannot lead outside of it
+unsafe fn open_in_root(root: &CString, path: &CString) -> io::Result<libc::c_int> {
+    let root_fd = libc::open(
+        root.as_ptr(),
+        libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+    );
+    if root_fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let how = OpenHow {
+        flags: (libc::O_PATH | libc::O_CLOEXEC) as u64,
+        mode: 0,
+        resolve: RESOLVE_IN_ROOT | RESOLVE_NO_MAGICLINKS,
+    };
+    let fd = libc::syscall(
+        libc::SYS_openat2,
+        root_fd,
+        path.as_ptr(),
+        &how as *const OpenHow,
+        std::mem::size_of::<OpenHow>(),
+    ) as libc::c_int;
+    let result = if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(fd)
+    };
+    libc::close(root_fd);
+    result
+}
+
+/// Whether a resolved source is under one of the allowed prefixes
+pub fn is_allowed(source: &Path, allowed: &[PathBuf]) -> bool {
+    allowed.iter().any(|prefix| source.starts_with(prefix))
+}
+
+/// A bind mount prepared for `pre_exec`
+pub struct BindMount {
+    source: CString,
+    dev: u64,
+    ino: u64,
+    /// Root filesystem of the job, under which the target is resolved
+    root: CString,
+    target: CString,
+    /// `MOUNT_ATTR_*` flags, for read-only and the other options
+    attrs: u64,
+    /// Submounts of the source are mounted too, and get the same flags
+    recursive: bool,
+}
+impl BindMount {
+    /// The target is resolved under the job root filesystem, or the host root
+    pub fn new(mount: &Mount, allowed: &[PathBuf], rootfs: Option<&Path>) -> Result<Self, String> {
+        let source = resolve_source(&mount.source)?;
+        if !is_allowed(&source.path, allowed) {
+            return Err(format!("Mount source {:?} is not allowed", source.path));
+        }
+
+        let mut attrs = 0;
+        for (set, attr) in &[
+            (mount.read_only, MOUNT_ATTR_RDONLY),
+            (mount.nosuid, MOUNT_ATTR_NOSUID),
+            (mount.nodev, MOUNT_ATTR_NODEV),
+            (mount.noexec, MOUNT_ATTR_NOEXEC),
+        ] {
+            if *set {
+                attrs |= attr;
+            }
+        }
+
+        Self::prepare(&source, &mount.destination, attrs, rootfs)
+    }
+
+    /// Mount a tmpfs of the job, which the server has mounted on the host
+    pub fn scratch(scratch: &Scratch, rootfs: Option<&Path>) -> Result<Self, String> {
+        let source = resolve_source(&scratch.path.to_string_lossy())?;
+        let attrs = MOUNT_ATTR_NOSUID | MOUNT_ATTR_NODEV;
+        Self::prepare(&source, &scratch.destination, attrs, rootfs)
+    }
+
+    fn prepare(
+        source: &Source,
+        destination: &str,
+        attrs: u64,
+        rootfs: Option<&Path>,
+    ) -> Result<Self, String> {
+        let destination = Path::new(destination);
+        if !destination.is_absolute() {
+            return Err(format!(
+                "Mount destination {:?} is not an absolute path",
+                destination
+            ));
+        }
+        if destination.components().any(|c| c == Component::ParentDir) {
+            return Err(format!(
+                "Mount destination {:?} must not contain `..`",
+                destination
+            ));
+        }
+
+        let c_path = |path: &Path| {
+            CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| format!("Mount path {:?} contains a nul byte", path))
+        };
+        let root = c_path(rootfs.unwrap_or_else(|| Path::new("/")))?;
+        let target = c_path(destination)?;
+        // Checked here for a clear error, and resolved again when mounting
+        let fd = unsafe { open_in_root(&root, &target) }
+            .map_err(|e| format!("Mount destination {:?}: {}", destination, e))?;
+        unsafe { libc::close(fd) };
+
+        Ok(Self {
+            source: c_path(&source.path)?,
+            dev: source.dev,
+            ino: source.ino,
+            root,
+            target,
+            attrs,
+            recursive: has_mount_setattr(),
+        })
+    }
+
+    /// Mount in the current mount namespace.
+    pub unsafe fn mount(&self) -> io::Result<()> {
+        // Opened again in this mount namespace, as mounts of other namespaces cannot be bind-mounted
+        let fd = libc::open(
+            self.source.as_ptr(),
+            libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let result = self.mount_fd(fd);
+        libc::close(fd);
+        result
+    }
+
+    unsafe fn mount_fd(&self, fd: libc::c_int) -> io::Result<()> {
+        let mut stat: libc::stat = std::mem::zeroed();
+        if libc::fstat(fd, &mut stat) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if stat.st_dev != self.dev || stat.st_ino != self.ino {
+            // Replaced after the request was validated
+            return Err(io::Error::from_raw_os_error(libc::ESTALE));
+        }
+
+        // Mounted through the opened target, so that it's the file resolved in the root
+        let target_fd = open_in_root(&self.root, &self.target)?;
+        let mut source_path = [0u8; 32];
+        fd_path(fd, &mut source_path);
+        let mut target_path = [0u8; 32];
+        fd_path(target_fd, &mut target_path);
+        let flags = if self.recursive {
+            libc::MS_BIND | libc::MS_REC
+        } else {
+            libc::MS_BIND
+        };
+        let result = libc::mount(
+            source_path.as_ptr() as *const libc::c_char,
+            target_path.as_ptr() as *const libc::c_char,
+            ptr::null(),
+            flags,
+            ptr::null(),
+        );
+        let error = io::Error::last_os_error();
+        libc::close(target_fd);
+        if result < 0 {
+            return Err(error);
+        }
+        if self.attrs == 0 {
+            return Ok(());
+        }
+
+        // Opened again, as the earlier file is under the new mount
+        let mount_fd = open_in_root(&self.root, &self.target)?;
+        let result = self.set_attrs(mount_fd);
+        libc::close(mount_fd);
+        result
+    }
+
+    /// Apply the flags to the new mount, opened as `fd`
+    unsafe fn set_attrs(&self, fd: libc::c_int) -> io::Result<()> {
+        if self.recursive {
+            let attr = MountAttr {
+                attr_set: self.attrs,
+                attr_clr: 0,
+                propagation: 0,
+                userns_fd: 0,
+            };
+            if libc::syscall(
+                libc::SYS_mount_setattr,
+                fd,
+                b"\0".as_ptr() as *const libc::c_char,
+                libc::AT_EMPTY_PATH | libc::AT_RECURSIVE,
+                &attr as *const MountAttr,
+                std::mem::size_of::<MountAttr>(),
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        } else {
+            // Flags other than MS_REC are ignored when creating a bind mount
+            let mut flags = libc::MS_BIND | libc::MS_REMOUNT;
+            for (attr, flag) in &[
+                (MOUNT_ATTR_RDONLY, libc::MS_RDONLY),
+                (MOUNT_ATTR_NOSUID, libc::MS_NOSUID),
+                (MOUNT_ATTR_NODEV, libc::MS_NODEV),
+                (MOUNT_ATTR_NOEXEC, libc::MS_NOEXEC),
+            ] {
+                if self.attrs & attr != 0 {
+                    flags |= flag;
+                }
+            }
+            let mut path = [0u8; 32];
+            fd_path(fd, &mut path);
+            if libc::mount(
+                ptr::null(),
+                path.as_ptr() as *const libc::c_char,
+                ptr::null(),
+                flags,
+                ptr::null(),
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write `/proc/self/fd/<fd>` to the buffer, nul-terminated, without allocating
+fn fd_path(fd: libc::c_int, buf: &mut [u8; 32]) {
+    const PREFIX: &[u8] = b"/proc/self/fd/";
+    let mut digits = [0u8; 10];
+    let digits = decimal(fd as u32, &mut digits);
+    buf[..PREFIX.len()].copy_from_slice(PREFIX);
+    buf[PREFIX.len()..PREFIX.len() + digits.len()].copy_from_slice(digits);
+    buf[PREFIX.len() + digits.len()] = 0;
+}
@@ -0,0 +1,224 @@
+use std::collections::BTreeSet;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use common::JobId;
+
+use crate::config::BridgeConfig;
+
+/// Run an `ip` command, returning stderr on failure
+async fn ip(args: &[&str]) -> Result<(), String> {
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| format!("Running ip failed: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "ip {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Load an `nft` ruleset, which is applied as a single transaction. Returns stderr on failure.
+async fn nft(ruleset: &str) -> Result<(), String> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Running nft failed: {}", e))?;
+    let mut stdin = child.stdin.take().unwrap();
+    stdin
+        .write_all(ruleset.as_bytes())
+        .await
+        .map_err(|e| format!("Writing nft ruleset failed: {}", e))?;
+    drop(stdin);
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| format!("Running nft failed: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "nft failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Drop packets from the interface to the host itself, except replies to
+/// connections made by the host. Forwarded packets are not affected, so jobs
+/// can still reach each other. The rules are in an `inet` table named after
+/// the interface, which replaces any previous table of the same name.
+async fn drop_input(interface: &str) -> Result<(), String> {
+    let table = format!("inet job-runner-{}", interface);
+    // Created first, so that deleting succeeds whether the table existed or not
+    nft(&format!(
+        "table {table}
+delete table {table}
+table {table} {{
+    chain input {{
+        type filter hook input priority 0; policy accept;
+        iifname \"{interface}\" ct state established,related accept
+        iifname \"{interface}\" drop
+    }}
+}}
+",
+        table = table,
+        interface = interface,
+    ))
+    .await
+}
+
+/// Parse an IPv4 subnet in CIDR notation, e.g. `10.88.0.0/16`
+fn parse_subnet(subnet: &str) -> Result<(Ipv4Addr, u8), String> {
+    let invalid = || format!("Invalid subnet {:?}", subnet);
+    let (addr, prefix_len) = subnet.split_once('/').ok_or_else(invalid)?;
+    let addr: Ipv4Addr = addr.parse().map_err(|_| invalid())?;
+    let prefix_len: u8 = prefix_len.parse().map_err(|_| invalid())?;
+    if !(8..=30).contains(&prefix_len) {
+        return Err(format!(
+            "Subnet prefix must be between 8 and 30 in {:?}",
+            subnet
+        ));
+    }
+    let mask = u32::MAX << (32 - prefix_len);
+    Ok((Ipv4Addr::from(u32::from(addr) & mask), prefix_len))
+}
+
+/// A server-managed bridge that connects the jobs
+#[derive(Debug)]
+pub struct Bridge {
+    name: String,
+    network: Ipv4Addr,
+    prefix_len: u8,
+    /// Host parts of addresses in use. The first one is the bridge itself.
+    allocated: Mutex<BTreeSet<u32>>,
+}
+impl Bridge {
+    /// Create the bridge if required, and assign the gateway address to it
+    pub async fn setup(config: &BridgeConfig) -> Result<Self, String> {
+        let (network, prefix_len) = parse_subnet(&config.subnet)?;
+        let bridge = Self {
+            name: config.name.clone(),
+            network,
+            prefix_len,
+            allocated: Mutex::new(BTreeSet::new()),
+        };
+
+        if !PathBuf::from("/sys/class/net").join(&bridge.name).exists() {
+            ip(&["link", "add", &bridge.name, "type", "bridge"]).await?;
+        }
+        let gateway = bridge.allocate()?;
+        ip(&[
+            "addr",
+            "replace",
+            &bridge.cidr(gateway),
+            "dev",
+            &bridge.name,
+        ])
+        .await?;
+        ip(&["link", "set", &bridge.name, "up"]).await?;
+        drop_input(&bridge.name)
+            .await
+            .map_err(|e| format!("Isolating host from bridge {} failed: {}", bridge.name, e))?;
+        Ok(bridge)
+    }
+
+    /// Address with the subnet prefix length
+    fn cidr(&self, addr: Ipv4Addr) -> String {
+        format!("{}/{}", addr, self.prefix_len)
+    }
+
+    fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) + 1)
+    }
+
+    /// Reserve the lowest free address
+    fn allocate(&self) -> Result<Ipv4Addr, String> {
+        let mut allocated = self.allocated.lock().unwrap();
+        // Skip network and broadcast addresses
+        let host_count = (1u32 << (32 - self.prefix_len)) - 2;
+        let host = (1..=host_count)
+            .find(|host| !allocated.contains(host))
+            .ok_or_else(|| "No free addresses in the job subnet".to_owned())?;
+        allocated.insert(host);
+        Ok(Ipv4Addr::from(u32::from(self.network) + host))
+    }
+
+    fn release(&self, addr: Ipv4Addr) {
+        let host = u32::from(addr) - u32::from(self.network);
+        self.allocated.lock().unwrap().remove(&host);
+    }
+
+    /// Create a network namespace for a job, connected to the bridge with a veth pair
+    pub async fn attach(self: &Arc<Self>, jobid: JobId) -> Result<Attachment, String> {
+        let address = self.allocate()?;
+        let attachment = Attachment {
+            netns: format!("job-{}", jobid),
+            address,
+            bridge: self.clone(),
+        };
+
+        let ns = attachment.netns.as_str();
+        // Interface names are limited to 15 characters
+        let veth = format!("vj{}", &jobid.to_string()[..8]);
+        let cidr = self.cidr(address);
+        let gateway = self.gateway().to_string();
+        let result = async {
+            ip(&["netns", "add", ns]).await?;
+            ip(&[
+                "link", "add", &veth, "type", "veth", "peer", "name", "eth0", "netns", ns,
+            ])
+            .await?;
+            ip(&["link", "set", &veth, "master", &self.name, "up"]).await?;
+            ip(&["-n", ns, "addr", "add", &cidr, "dev", "eth0"]).await?;
+            ip(&["-n", ns, "link", "set", "eth0", "up"]).await?;
+            ip(&["-n", ns, "link", "set", "lo", "up"]).await?;
+            ip(&["-n", ns, "route", "add", "default", "via", &gateway]).await
+        }
+        .await;
+
+        result.map(|_| attachment)
+    }
+}
+
+/// Network namespace of a single job
+#[derive(Debug)]
+pub struct Attachment {
+    netns: String,
+    pub address: Ipv4Addr,
+    bridge: Arc<Bridge>,
+}
+impl Attachment {
+    /// Path of the namespace file, to be passed to `setns`
+    pub fn netns_path(&self) -> PathBuf {
+        PathBuf::from("/run/netns").join(&self.netns)
+    }
+}
+
+impl Drop for Attachment {
+    /// Remove the namespace in the background, and release the address.
+    /// The veth pair is removed together with the namespace.
+    fn drop(&mut self) {
+        let netns = self.netns.clone();
+        if self.netns_path().exists() {
+            tokio::spawn(async move {
+                if let Err(err) = ip(&["netns", "delete", &netns]).await {
+                    log::warn!("Could not remove job network namespace: {}", err);
+                }
+            });
+        }
+        self.bridge.release(self.address);
+    }
+}
@@ -0,0 +1,328 @@
+//! Jobs from OCI runtime bundles, i.e. a `config.json` and a root filesystem.
+//!
+//! Only the parts of the [runtime spec](https://github.com/opencontainers/runtime-spec/blob/main/config.md)
+//! that map to the options of `JobStartRequest` are supported, the rest is ignored.
+//! The settings are converted to request fields, so that the same policy checks apply.
+
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use common::*;
+
+use crate::limits;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Spec {
+    process: Option<Process>,
+    root: Option<Root>,
+    hostname: Option<String>,
+    mounts: Vec<SpecMount>,
+    linux: Option<Linux>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Process {
+    args: Vec<String>,
+    env: Vec<String>,
+    cwd: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Root {
+    path: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct SpecMount {
+    destination: String,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    source: Option<String>,
+    options: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Linux {
+    resources: Option<Resources>,
+    namespaces: Vec<Namespace>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Resources {
+    memory: Option<Memory>,
+    cpu: Option<Cpu>,
+    pids: Option<Pids>,
+    #[serde(rename = "blockIO")]
+    block_io: Option<BlockIo>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Memory {
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Cpu {
+    quota: Option<i64>,
+    period: Option<u64>,
+    cpus: Option<String>,
+    mems: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Pids {
+    limit: i64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct BlockIo {
+    throttle_read_bps_device: Vec<ThrottleDevice>,
+    throttle_write_bps_device: Vec<ThrottleDevice>,
+    throttle_read_iops_device: Vec<ThrottleDevice>,
+    throttle_write_iops_device: Vec<ThrottleDevice>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ThrottleDevice {
+    major: u64,
+    minor: u64,
+    rate: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Namespace {
+    #[serde(rename = "type")]
+    kind: String,
+    path: Option<String>,
+}
+
+/// Convert the mounts of the bundle. Mounts under `/dev` and filesystems
+/// the job gets anyway or cannot have, like `proc` and `sysfs`, are skipped.
+fn convert_mounts(bundle: &Path, spec: &Spec, req: &mut JobStartRequest) -> Result<(), String> {
+    for mount in &spec.mounts {
+        let skipped = matches!(
+            mount.kind.as_deref(),
+            Some("proc")
+                | Some("sysfs")
+                | Some("mqueue")
+                | Some("devpts")
+                | Some("cgroup")
+                | Some("cgroup2")
+        );
+        if skipped || Path::new(&mount.destination).starts_with("/dev") {
+            continue;
+        }
+
+        let has_option = |name: &str| mount.options.iter().any(|o| o == name);
+        // Bind mounts may have any type, if they have the bind option
+        let is_bind =
+            mount.kind.as_deref() == Some("bind") || has_option("bind") || has_option("rbind");
+        if mount.kind.as_deref() == Some("tmpfs") {
+            let size = mount
+                .options
+                .iter()
+                .find_map(|o| o.strip_prefix("size="))
+                .ok_or_else(|| format!("Tmpfs {:?} has no size", mount.destination))?;
+            req.tmpfs.push(Tmpfs {
+                destination: mount.destination.clone(),
+                size_bytes: parse_size(size)?,
+            });
+        } else if is_bind {
+            let source = mount
+                .source
+                .as_ref()
+                .ok_or_else(|| format!("Mount {:?} has no source", mount.destination))?;
+            req.mounts.push(Mount {
+                // Relative sources are relative to the bundle
+                source: bundle.join(source).to_string_lossy().into_owned(),
+                destination: mount.destination.clone(),
+                read_only: has_option("ro"),
+                nosuid: has_option("nosuid"),
+                nodev: has_option("nodev"),
+                noexec: has_option("noexec"),
+            });
+        } else {
+            return Err(format!(
+                "Unsupported mount type {:?} for {:?}",
+                mount.kind.as_deref().unwrap_or(""),
+                mount.destination
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Limits of the block device a bundle throttles, looked up by its device numbers
+fn io_limit<'a>(
+    io: &'a mut Vec<IoLimit>,
+    throttle: &ThrottleDevice,
+) -> Result<&'a mut IoLimit, String> {
+    let device = limits::device_name(throttle.major, throttle.minor)
+        .map_err(|e| format!("Reading /proc/partitions failed: {}", e))?
+        .ok_or_else(|| {
+            format!(
+                "No such block device: {}:{}",
+                throttle.major, throttle.minor
+            )
+        })?;
+    Ok(IoLimit::entry(io, device))
+}
+
+/// Convert the resources of the bundle, for the limits the request doesn't set
+fn convert_resources(resources: &Resources, req: &mut JobStartRequest) -> Result<(), String> {
+    if let Some(limit) = resources.memory.as_ref().and_then(|m| m.limit) {
+        if req.memory_bytes == 0 && limit > 0 {
+            req.memory_bytes = limit as u64;
+        }
+    }
+
+    if let Some(cpu) = &resources.cpu {
+        if let (None, Some(quota)) = (&req.cpu, cpu.quota) {
+            if quota > 0 {
+                req.cpu = Some(CpuLimit {
+                    quota_us: quota as u64,
+                    period_us: cpu.period.unwrap_or(0),
+                });
+            }
+        }
+        let cpus = cpu.cpus.clone().unwrap_or_default();
+        let mems = cpu.mems.clone().unwrap_or_default();
+        if req.cpuset.is_none() && (!cpus.is_empty() || !mems.is_empty()) {
+            req.cpuset = Some(CpuSet {
+                cpus,
+                mems,
+                exclusive: false,
+            });
+        }
+    }
+
+    if let Some(pids) = &resources.pids {
+        if req.max_processes == 0 && pids.limit > 0 {
+            req.max_processes = pids.limit as u64;
+        }
+    }
+
+    if let (true, Some(block_io)) = (req.io.is_empty(), &resources.block_io) {
+        let mut io: Vec<IoLimit> = Vec::new();
+        for throttle in &block_io.throttle_read_bps_device {
+            io_limit(&mut io, throttle)?.read_bps = throttle.rate;
+        }
+        for throttle in &block_io.throttle_write_bps_device {
+            io_limit(&mut io, throttle)?.write_bps = throttle.rate;
+        }
+        for throttle in &block_io.throttle_read_iops_device {
+            io_limit(&mut io, throttle)?.read_iops = throttle.rate;
+        }
+        for throttle in &block_io.throttle_write_iops_device {
+            io_limit(&mut io, throttle)?.write_iops = throttle.rate;
+        }
+        req.io = io;
+    }
+    Ok(())
+}
+
+/// Convert the namespaces of the bundle. The job always gets its own mount,
+/// UTS, IPC and network namespaces. Other namespaces missing from the bundle
+/// are shared with the host where possible. Host networking is only used if
+/// the request asks for it, as it's subject to the server policy.
+fn convert_namespaces(namespaces: &[Namespace], req: &mut JobStartRequest) -> Result<(), String> {
+    for namespace in namespaces {
+        if namespace.path.is_some() {
+            return Err(format!(
+                "Joining an existing {} namespace is not supported",
+                namespace.kind
+            ));
+        }
+        match namespace.kind.as_str() {
+            "pid" | "network" | "user" | "mount" | "uts" | "ipc" | "cgroup" => {}
+            kind => return Err(format!("Unknown namespace type {:?}", kind)),
+        }
+    }
+    let has = |kind: &str| namespaces.iter().any(|n| n.kind == kind);
+
+    if !has("pid") {
+        req.host_pid_namespace = true;
+    }
+    if req.network == NetworkMode::None as i32 {
+        req.network = NetworkMode::Loopback as i32;
+    }
+    // ID mappings of the bundle are ignored, the server allocates the host IDs
+    if has("user") {
+        req.user_namespace = true;
+    }
+    Ok(())
+}
+
+/// Resolve the bundle directory with symlinks and `..` removed,
+/// so that it can be checked against the allowed prefixes
+pub fn resolve(bundle: &Path) -> Result<PathBuf, String> {
+    if !bundle.is_absolute() {
+        return Err(format!("Bundle {:?} is not an absolute path", bundle));
+    }
+    bundle
+        .canonicalize()
+        .map_err(|e| format!("Bundle {:?}: {}", bundle, e))
+}
+
+/// Fill in the fields the request doesn't set from the `config.json` of a bundle.
+/// Mounts and environment variables of the bundle are added to those of the request.
+/// The bundle must have been resolved with `resolve`.
+pub fn apply(bundle: &Path, req: &mut JobStartRequest) -> Result<(), String> {
+    let config_path = bundle.join("config.json");
+    let config = std::fs::read(&config_path)
+        .map_err(|e| format!("Reading {:?} failed: {}", config_path, e))?;
+    let spec: Spec = serde_json::from_slice(&config)
+        .map_err(|e| format!("Invalid bundle config {:?}: {}", config_path, e))?;
+
+    let process = spec.process.as_ref();
+    if req.path.is_empty() {
+        let args = process.map(|p| p.args.as_slice()).unwrap_or_default();
+        let (path, args) = args
+            .split_first()
+            .ok_or_else(|| "Bundle has no process args".to_owned())?;
+        req.path = path.clone();
+        req.args = args.to_vec();
+    }
+    if let Some(root) = spec.root.as_ref().filter(|_| req.rootfs.is_empty()) {
+        req.rootfs = bundle.join(&root.path).to_string_lossy().into_owned();
+    }
+    if let Some(hostname) = spec.hostname.as_ref().filter(|_| req.hostname.is_empty()) {
+        req.hostname = hostname.clone();
+    }
+
+    convert_mounts(bundle, &spec, req)?;
+    if let Some(linux) = &spec.linux {
+        if let Some(resources) = &linux.resources {
+            convert_resources(resources, req)?;
+        }
+        convert_namespaces(&linux.namespaces, req)?;
+    } else {
+        convert_namespaces(&[], req)?;
+    }
+
+    for var in process.map(|p| p.env.as_slice()).unwrap_or_default() {
+        let (key, value) = var
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid environment variable {:?}", var))?;
+        req.env
+            .entry(key.to_owned())
+            .or_insert_with(|| value.to_owned());
+    }
+    if let Some(cwd) = process.map(|p| &p.cwd).filter(|_| req.cwd.is_empty()) {
+        req.cwd = cwd.clone();
+    }
+    Ok(())
+}
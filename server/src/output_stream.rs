@@ -73,6 +73,17 @@ impl OutputHandler {
         state.completed = true;
         self.notify.notify_waiters();
     }
+
+    /// Resolves when the stream has been marked complete
+    pub async fn wait_completed(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.state.read().await.completed {
+                return;
+            }
+            notified.await;
+        }
+    }
 }
 
 /// Start a task that streams from an `OutputHandler` to a mpsc channel.
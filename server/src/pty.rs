@@ -0,0 +1,124 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use common::TerminalSize;
+
+/// Master side of the pseudo-terminal of a job
+#[derive(Clone)]
+pub struct Terminal {
+    master: Arc<AsyncFd<File>>,
+}
+impl Terminal {
+    /// Open a new pseudo-terminal. Returns the terminal and its slave side for the job.
+    pub fn open(size: &TerminalSize) -> io::Result<(Self, File)> {
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = unsafe { File::from_raw_fd(fd) };
+        if unsafe { libc::grantpt(fd) } < 0 || unsafe { libc::unlockpt(fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut name = [0 as libc::c_char; 64];
+        let result = unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) };
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+        let slave_fd = unsafe {
+            libc::open(
+                name.as_ptr(),
+                libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC,
+            )
+        };
+        if slave_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let slave = unsafe { File::from_raw_fd(slave_fd) };
+
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let terminal = Self {
+            master: Arc::new(AsyncFd::new(master)?),
+        };
+        terminal.resize(size)?;
+        Ok((terminal, slave))
+    }
+
+    /// Set the window size, which sends `SIGWINCH` to the foreground processes of the terminal
+    pub fn resize(&self, size: &TerminalSize) -> io::Result<()> {
+        let winsize = libc::winsize {
+            ws_row: size.rows.min(u16::MAX as u32) as u16,
+            ws_col: size.cols.min(u16::MAX as u32) as u16,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let fd = self.master.as_raw_fd();
+        if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &winsize) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Write input for the job, as if it was typed on the terminal
+    pub async fn write_all(&self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let mut guard = self.master.writable().await?;
+            match guard.try_io(|master| master.get_ref().write(data)) {
+                Ok(Ok(n)) => data = &data[n..],
+                Ok(Err(err)) => return Err(err),
+                Err(_would_block) => continue,
+            }
+        }
+        Ok(())
+    }
+
+    /// Reader for the output of the job
+    pub fn reader(&self) -> TerminalReader {
+        TerminalReader {
+            master: self.master.clone(),
+        }
+    }
+}
+
+/// Output of the job on its terminal. Ends when all processes of the job have closed the terminal.
+pub struct TerminalReader {
+    master: Arc<AsyncFd<File>>,
+}
+impl AsyncRead for TerminalReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = match self.master.poll_read_ready(cx) {
+                Poll::Ready(guard) => guard?,
+                Poll::Pending => return Poll::Pending,
+            };
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|master| master.get_ref().read(unfilled)) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                // Reading the master fails once the slave side has been closed
+                Ok(Err(err)) if err.raw_os_error() == Some(libc::EIO) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
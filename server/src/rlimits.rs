@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::io;
+
+#[cfg(target_env = "gnu")]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(target_env = "gnu"))]
+type Resource = libc::c_int;
+
+/// Resources that jobs can limit
+const RESOURCES: &[(&str, Resource)] = &[
+    ("RLIMIT_AS", libc::RLIMIT_AS),
+    ("RLIMIT_CORE", libc::RLIMIT_CORE),
+    ("RLIMIT_CPU", libc::RLIMIT_CPU),
+    ("RLIMIT_DATA", libc::RLIMIT_DATA),
+    ("RLIMIT_FSIZE", libc::RLIMIT_FSIZE),
+    ("RLIMIT_MEMLOCK", libc::RLIMIT_MEMLOCK),
+    ("RLIMIT_NOFILE", libc::RLIMIT_NOFILE),
+    ("RLIMIT_STACK", libc::RLIMIT_STACK),
+];
+
+/// Parse a resource name, e.g. `RLIMIT_NOFILE` or `nofile`
+fn parse(name: &str) -> Result<Resource, String> {
+    let upper = name.to_ascii_uppercase();
+    let full = if upper.starts_with("RLIMIT_") {
+        upper
+    } else {
+        format!("RLIMIT_{}", upper)
+    };
+    RESOURCES
+        .iter()
+        .find(|(n, _)| *n == full)
+        .map(|(_, resource)| *resource)
+        .ok_or_else(|| format!("Unsupported rlimit {:?}", name))
+}
+
+/// Checks that all resource names are supported
+pub fn validate(limits: &HashMap<String, u64>) -> Result<(), String> {
+    limits.keys().try_for_each(|name| parse(name).map(|_| ()))
+}
+
+/// A single limit applied to the job
+pub struct Rlimit {
+    resource: Resource,
+    limit: libc::rlimit,
+}
+
+/// Hard limit of the server process itself
+fn current_max(resource: Resource) -> u64 {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(resource, &mut limit) } < 0 {
+        return libc::RLIM_INFINITY;
+    }
+    limit.rlim_max
+}
+
+/// Limits of a job: the requested ones, capped by the server maximums.
+/// Server maximums apply also to resources the request doesn't limit.
+/// Values are also capped by the limits of the server, which cannot
+/// be raised without `CAP_SYS_RESOURCE`.
+pub fn resolve(
+    requested: &HashMap<String, u64>,
+    maximums: &HashMap<String, u64>,
+) -> Result<Vec<Rlimit>, String> {
+    // Value and maximum of each resource
+    let mut values: HashMap<Resource, (u64, u64)> = HashMap::new();
+    for (name, &max) in maximums {
+        values.insert(parse(name)?, (max, max));
+    }
+    for (name, &value) in requested {
+        let (limit, _) = values
+            .entry(parse(name)?)
+            .or_insert((value, libc::RLIM_INFINITY));
+        *limit = value.min(*limit);
+    }
+
+    Ok(values
+        .into_iter()
+        .map(|(resource, (value, max))| {
+            let max = max.min(current_max(resource));
+            let value = value.min(max);
+            // The soft limit sends SIGXCPU, the hard one SIGKILL.
+            // Leave some time between them, so the status shows why the job was killed.
+            let hard = if resource == libc::RLIMIT_CPU {
+                value.saturating_add(1).min(max)
+            } else {
+                value
+            };
+            Rlimit {
+                resource,
+                limit: libc::rlimit {
+                    rlim_cur: value,
+                    rlim_max: hard,
+                },
+            }
+        })
+        .collect())
+}
+
+/// Hard CPU time limit in seconds, at which the kernel sends `SIGKILL`
+pub fn cpu_hard_seconds(limits: &[Rlimit]) -> Option<u64> {
+    limits
+        .iter()
+        .find(|rlimit| rlimit.resource == libc::RLIMIT_CPU)
+        .map(|rlimit| rlimit.limit.rlim_max)
+        .filter(|&seconds| seconds != libc::RLIM_INFINITY)
+}
+
+/// Apply the limits to the calling process.
+pub unsafe fn apply(limits: &[Rlimit]) -> io::Result<()> {
+    for rlimit in limits {
+        if libc::setrlimit(rlimit.resource, &rlimit.limit) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(pairs: &[(&str, u64)]) -> HashMap<String, u64> {
+        pairs.iter().map(|&(k, v)| (k.to_owned(), v)).collect()
+    }
+
+    #[test]
+    fn test_resolve_cpu_hard_limit() {
+        let resolved = resolve(&limits(&[("cpu", 5)]), &limits(&[("cpu", 10)])).unwrap();
+        assert_eq!(resolved[0].limit.rlim_cur, 5);
+        assert_eq!(cpu_hard_seconds(&resolved), Some(6));
+
+        // Never above the maximum of the config
+        let resolved = resolve(&limits(&[("cpu", 10)]), &limits(&[("cpu", 10)])).unwrap();
+        assert_eq!(resolved[0].limit.rlim_cur, 10);
+        assert_eq!(cpu_hard_seconds(&resolved), Some(10));
+
+        let resolved = resolve(&HashMap::new(), &limits(&[("cpu", 10)])).unwrap();
+        assert_eq!(cpu_hard_seconds(&resolved), Some(10));
+    }
+}
@@ -0,0 +1,717 @@
+//! Isolation setup done in the child process, between `fork` and `exec`.
+//!
+//! The child of a multithreaded process may only call async-signal-safe
+//! functions, so everything that allocates is prepared beforehand in `Sandbox::new`.
+//! The same applies to the `unsafe` functions of other modules called from here.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use common::{JobId, JobStartRequest, NetworkMode};
+use seccompiler::BpfProgram;
+
+use crate::capabilities;
+use crate::cgroup::{self, Cgroup};
+use crate::config::Config;
+use crate::job::Reserved;
+use crate::landlock::Ruleset;
+use crate::mounts::BindMount;
+use crate::rlimits::{self, Rlimit};
+use crate::seccomp;
+use crate::users::Credentials;
+
+/// Device nodes bind-mounted from the host into the job root filesystem
+const DEVICES: &[&str] = &["null", "zero", "full", "random", "urandom", "tty"];
+
+/// Convert a libc return value to `io::Result`
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// Literal to a C string pointer
+macro_rules! c_str {
+    ($s:expr) => {
+        concat!($s, "\0").as_ptr() as *const libc::c_char
+    };
+}
+
+/// Checks that the hostname consists of valid DNS labels
+fn validate_hostname(hostname: &str) -> Result<(), String> {
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if hostname.len() > 64 || !hostname.split('.').all(valid_label) {
+        return Err(format!("Invalid hostname {:?}", hostname));
+    }
+    Ok(())
+}
+
+fn c_path(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).expect("Path contains a nul byte")
+}
+
+/// Resolve symlinks and `..` in a root filesystem path,
+/// so that it can be checked against the allowed prefixes
+pub fn resolve_rootfs(root: &Path) -> Result<PathBuf, String> {
+    if !root.is_absolute() {
+        return Err(format!(
+            "Root filesystem {:?} is not an absolute path",
+            root
+        ));
+    }
+    let resolved = root
+        .canonicalize()
+        .map_err(|e| format!("Root filesystem {:?}: {}", root, e))?;
+    if !resolved.is_dir() {
+        return Err(format!("Root filesystem {:?} is not a directory", root));
+    }
+    if resolved == Path::new("/") {
+        return Err("Root filesystem must not be the host root".to_owned());
+    }
+    if resolved.as_os_str().as_bytes().contains(&0) {
+        return Err("Root filesystem path contains a nul byte".to_owned());
+    }
+    Ok(resolved)
+}
+
+/// Paths used when switching to the job root filesystem
+struct RootFs {
+    root: CString,
+    dev: CString,
+    mqueue: CString,
+    proc: CString,
+    /// Host device node and the mount point in the new root
+    devices: Vec<(CString, CString)>,
+    /// Keep the root filesystem writable, instead of remounting it read-only
+    writable: bool,
+}
+impl RootFs {
+    /// The root must be resolved with `resolve_rootfs`
+    fn new(root: &Path, writable: bool) -> Result<Self, String> {
+        // Mount points must exist, as nothing is created in the host directory
+        for dir in &["dev", "proc"] {
+            if !root.join(dir).is_dir() {
+                return Err(format!(
+                    "Root filesystem {:?} has no /{} directory",
+                    root, dir
+                ));
+            }
+        }
+        let dev = root.join("dev");
+        Ok(Self {
+            root: c_path(root),
+            dev: c_path(&dev),
+            mqueue: c_path(&dev.join("mqueue")),
+            proc: c_path(&root.join("proc")),
+            devices: DEVICES
+                .iter()
+                .map(|name| {
+                    (
+                        c_path(&Path::new("/dev").join(name)),
+                        c_path(&dev.join(name)),
+                    )
+                })
+                .collect(),
+            writable,
+        })
+    }
+
+    /// Make the root filesystem a mount point, as pivot_root requires.
+    /// Host mounts under it are left out, and mounts of the job are made on top of it.
+    unsafe fn bind(&self) -> io::Result<()> {
+        check(libc::mount(
+            self.root.as_ptr(),
+            self.root.as_ptr(),
+            ptr::null(),
+            libc::MS_BIND,
+            ptr::null(),
+        ))?;
+        Ok(())
+    }
+
+    /// Populate `/dev` and make the root filesystem the process root
+    unsafe fn pivot(&self) -> io::Result<()> {
+        check(libc::mount(
+            c_str!("tmpfs"),
+            self.dev.as_ptr(),
+            c_str!("tmpfs"),
+            libc::MS_NOSUID | libc::MS_NOEXEC,
+            c_str!("mode=755") as *const libc::c_void,
+        ))?;
+        for (host, target) in &self.devices {
+            let fd = check(libc::open(
+                target.as_ptr(),
+                libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC,
+                0o644,
+            ))?;
+            libc::close(fd);
+            check(libc::mount(
+                host.as_ptr(),
+                target.as_ptr(),
+                ptr::null(),
+                libc::MS_BIND,
+                ptr::null(),
+            ))?;
+        }
+
+        // POSIX message queues of the job IPC namespace, on the /dev tmpfs
+        libc::mkdir(self.mqueue.as_ptr(), 0o755);
+        check(libc::mount(
+            c_str!("mqueue"),
+            self.mqueue.as_ptr(),
+            c_str!("mqueue"),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            ptr::null(),
+        ))?;
+
+        // Without root, proc can only be mounted while the host /proc is still visible
+        mount_proc(self.proc.as_ptr())?;
+
+        // Stack the new root on top of the old one, then detach the old one
+        check(libc::chdir(self.root.as_ptr()))?;
+        check(libc::syscall(libc::SYS_pivot_root, c_str!("."), c_str!(".")) as libc::c_int)?;
+        check(libc::umount2(c_str!("."), libc::MNT_DETACH))?;
+        check(libc::chdir(c_str!("/")))?;
+
+        if !self.writable {
+            // Flags of the host mount are locked in a user namespace, so they're kept
+            let mut stat: libc::statvfs = std::mem::zeroed();
+            check(libc::statvfs(c_str!("/"), &mut stat))?;
+            let kept = stat.f_flag
+                & (libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_NOATIME);
+            check(libc::mount(
+                ptr::null(),
+                c_str!("/"),
+                ptr::null(),
+                libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | kept,
+                ptr::null(),
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Sandbox {
+    /// `cgroup.procs` of the job cgroup
+    cgroup_procs: Option<CString>,
+    /// UID and GID maps of a user namespace entered first, if the server isn't root.
+    /// Only the server's own IDs are mapped, but the namespace grants the privileges
+    /// needed for creating the other namespaces.
+    rootless: Option<(CString, CString)>,
+    /// Run the job as PID 1 of a new PID namespace
+    pid_namespace: bool,
+    /// Stdin is a pseudo-terminal, to become the controlling terminal
+    terminal: bool,
+    /// Switch to this root filesystem
+    rootfs: Option<RootFs>,
+    /// Bind mounts from the host, and the tmpfs mounts of the job
+    mounts: Vec<BindMount>,
+    /// Working directory in the job filesystem
+    cwd: Option<CString>,
+    network: NetworkMode,
+    /// Network namespace prepared by the server, for bridged networking
+    netns: Option<File>,
+    /// Contents of `uid_map` and `gid_map` if the job runs in a user namespace
+    id_map: Option<CString>,
+    /// Hostname in the UTS namespace of the job
+    hostname: CString,
+    /// Filesystem access rules enforced with Landlock
+    landlock: Option<Ruleset>,
+    /// Seccomp filter installed right before exec
+    seccomp: Option<BpfProgram>,
+    rlimits: Vec<Rlimit>,
+    /// User and groups to run as, instead of those of the server
+    credentials: Option<Credentials>,
+    /// Capabilities kept, as a bit set
+    capabilities: u64,
+    last_cap: u32,
+}
+
+impl Sandbox {
+    pub fn new(
+        jobid: JobId,
+        req: &JobStartRequest,
+        config: &Config,
+        cgroup: Option<&Cgroup>,
+        reserved: &Reserved,
+        cwd: Option<&str>,
+    ) -> Result<Self, String> {
+        let network =
+            NetworkMode::from_i32(req.network).ok_or_else(|| "Invalid network mode".to_owned())?;
+        let netns = match (network, &reserved.network) {
+            (NetworkMode::Bridge, Some(attachment)) => Some(
+                File::open(attachment.netns_path())
+                    .map_err(|e| format!("Opening network namespace failed: {}", e))?,
+            ),
+            (NetworkMode::Bridge, None) => {
+                return Err("Bridged network namespace is missing".to_owned())
+            }
+            _ => None,
+        };
+
+        let id_map = match (req.user_namespace, &reserved.id_mapping) {
+            (true, Some(mapping)) => {
+                Some(CString::new(mapping.map_line()).expect("ID map contains a nul byte"))
+            }
+            (true, None) => return Err("User namespace ID mapping is missing".to_owned()),
+            (false, _) => None,
+        };
+
+        let rootless = if unsafe { libc::geteuid() } != 0 {
+            if id_map.is_some() {
+                return Err("User namespace ID mapping requires a root server".to_owned());
+            }
+            let map = |id: u32| CString::new(format!("{} {} 1", id, id)).unwrap();
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            Some((map(uid), map(gid)))
+        } else {
+            None
+        };
+
+        let hostname = if !req.hostname.is_empty() {
+            validate_hostname(&req.hostname)?;
+            req.hostname.clone()
+        } else {
+            jobid.to_string()
+        };
+
+        let seccomp_profile = if !req.seccomp_profile.is_empty() {
+            Some(&req.seccomp_profile)
+        } else {
+            config
+                .seccomp
+                .as_ref()
+                .and_then(|c| c.default_profile.as_ref())
+        };
+        let seccomp = match seccomp_profile {
+            Some(name) => Some(
+                config
+                    .seccomp_profiles
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| format!("No such seccomp profile: {}", name))?,
+            ),
+            None => None,
+        };
+
+        let rlimits = rlimits::resolve(&req.rlimits, &config.rlimits)?;
+
+        let credentials = req.user.as_ref().map(Credentials::resolve).transpose()?;
+
+        let capabilities = capabilities::parse_set(&req.capabilities)?;
+        let last_cap = capabilities::last_cap()?;
+        if capabilities >> last_cap >> 1 != 0 {
+            return Err("Capability is not supported by the kernel".to_owned());
+        }
+
+        // Requested root filesystems have been checked against the policy
+        let rootfs = if !req.rootfs.is_empty() {
+            Some(resolve_rootfs(Path::new(&req.rootfs))?)
+        } else {
+            config
+                .defaults
+                .rootfs
+                .as_deref()
+                .map(resolve_rootfs)
+                .transpose()?
+        };
+
+        // Tmpfs mounts last, so that they can be mounted on top of bind-mounted directories
+        let mut mounts = req
+            .mounts
+            .iter()
+            .map(|m| BindMount::new(m, &config.policy.mount_sources, rootfs.as_deref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        for scratch in &reserved.tmpfs {
+            mounts.push(BindMount::scratch(scratch, rootfs.as_deref())?);
+        }
+
+        let landlock = if !req.path_rules.is_empty() {
+            Some(Ruleset::new(&req.path_rules, rootfs.as_deref())?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            cgroup_procs: cgroup.map(|c| c.procs_path()),
+            rootless,
+            pid_namespace: !req.host_pid_namespace,
+            terminal: req.tty.is_some(),
+            rootfs: rootfs
+                .as_deref()
+                .map(|root| RootFs::new(root, req.writable_rootfs))
+                .transpose()?,
+            mounts,
+            cwd: cwd
+                .map(|cwd| {
+                    CString::new(cwd)
+                        .map_err(|_| "Working directory contains a nul byte".to_owned())
+                })
+                .transpose()?,
+            network,
+            netns,
+            id_map,
+            hostname: CString::new(hostname).expect("Hostname contains a nul byte"),
+            landlock,
+            seccomp,
+            rlimits,
+            credentials,
+            capabilities,
+            last_cap,
+        })
+    }
+
+    /// Configure the current process. Called from `pre_exec`.
+    pub unsafe fn enter(&self) -> io::Result<()> {
+        // Don't outlive the server. A job below a shim is killed with the shim.
+        check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+
+        if self.terminal {
+            // A new session is also a new process group, for killing the job
+            check(libc::setsid())?;
+            check(libc::ioctl(0, libc::TIOCSCTTY, 0))?;
+        }
+
+        if let Some(procs) = &self.cgroup_procs {
+            cgroup::join_self(procs)?;
+        }
+
+        if let Some((uid_map, gid_map)) = &self.rootless {
+            check(libc::unshare(libc::CLONE_NEWUSER))?;
+            // An unprivileged process must deny setgroups before writing its GID map
+            let pid = libc::getpid();
+            write_proc_file(pid, b"setgroups", b"deny")?;
+            write_proc_file(pid, b"uid_map", uid_map.as_bytes())?;
+            write_proc_file(pid, b"gid_map", gid_map.as_bytes())?;
+        }
+
+        if self.pid_namespace {
+            // Only children of the calling process are placed in the namespace
+            check(libc::unshare(libc::CLONE_NEWPID))?;
+        }
+        let id_map_channel = if self.pid_namespace || self.id_map.is_some() {
+            // Now running as PID 1 of the namespace, if one was created
+            fork_shim(
+                self.id_map.as_ref(),
+                rlimits::cpu_hard_seconds(&self.rlimits),
+            )?
+        } else {
+            None
+        };
+
+        if let Some(netns) = &self.netns {
+            check(libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET))?;
+        } else if self.network != NetworkMode::Host {
+            check(libc::unshare(libc::CLONE_NEWNET))?;
+            if self.network == NetworkMode::Loopback {
+                loopback_up()?;
+            }
+        }
+
+        check(libc::unshare(libc::CLONE_NEWUTS | libc::CLONE_NEWIPC))?;
+        let hostname = self.hostname.as_bytes();
+        check(libc::sethostname(
+            hostname.as_ptr() as *const libc::c_char,
+            hostname.len(),
+        ))?;
+
+        if self.pid_namespace || self.rootfs.is_some() || !self.mounts.is_empty() {
+            check(libc::unshare(libc::CLONE_NEWNS))?;
+            // Don't propagate any changes back to the host
+            check(libc::mount(
+                ptr::null(),
+                c_str!("/"),
+                ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                ptr::null(),
+            ))?;
+
+            // On top of the new root, which takes its submounts with it
+            if let Some(rootfs) = &self.rootfs {
+                rootfs.bind()?;
+            }
+            for mount in &self.mounts {
+                mount.mount()?;
+            }
+            match &self.rootfs {
+                Some(rootfs) => rootfs.pivot()?,
+                None => mount_proc(c_str!("/proc"))?,
+            }
+        }
+
+        // Command::current_dir would apply before the root filesystem is switched
+        if let Some(cwd) = &self.cwd {
+            check(libc::chdir(cwd.as_ptr()))?;
+        }
+
+        // Raising hard limits requires CAP_SYS_RESOURCE
+        rlimits::apply(&self.rlimits)?;
+
+        // Everything above requires privileges in the host user namespace,
+        // or in the rootless user namespace
+        if let Some(channel) = id_map_channel {
+            check(libc::unshare(libc::CLONE_NEWUSER))?;
+            channel.request_id_map()?;
+            // Become root of the namespace, without any host groups
+            check(libc::setgroups(0, ptr::null()))?;
+            check(libc::setresgid(0, 0, 0))?;
+            check(libc::setresuid(0, 0, 0))?;
+            // Changing credentials clears the parent death signal
+            check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+        }
+
+        capabilities::drop_bounding(self.capabilities, self.last_cap)?;
+        if let Some(credentials) = &self.credentials {
+            // Keep the permitted set over the uid change, so that it can be restricted below
+            check(libc::prctl(libc::PR_SET_KEEPCAPS, 1))?;
+            credentials.switch()?;
+            check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+        }
+        capabilities::restrict(self.capabilities, self.last_cap)?;
+        check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+
+        // Paths are resolved in the job filesystem, after all mounts
+        if let Some(landlock) = &self.landlock {
+            landlock.enforce()?;
+        }
+
+        // Last, so that the filter only needs to allow exec
+        if let Some(filter) = &self.seccomp {
+            seccomp::install(filter)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Mount a `/proc` of the PID namespace of the current process
+unsafe fn mount_proc(target: *const libc::c_char) -> io::Result<()> {
+    check(libc::mount(
+        c_str!("proc"),
+        target,
+        c_str!("proc"),
+        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        ptr::null(),
+    ))?;
+    Ok(())
+}
+
+/// Bring up the loopback interface, which is down in a new network namespace
+unsafe fn loopback_up() -> io::Result<()> {
+    let sock = check(libc::socket(
+        libc::AF_INET,
+        libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+        0,
+    ))?;
+    let mut ifr: libc::ifreq = std::mem::zeroed();
+    ifr.ifr_name[0] = b'l' as libc::c_char;
+    ifr.ifr_name[1] = b'o' as libc::c_char;
+    let result = check(libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut ifr)).and_then(|_| {
+        ifr.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+        check(libc::ioctl(sock, libc::SIOCSIFFLAGS, &ifr))
+    });
+    libc::close(sock);
+    result.map(|_| ())
+}
+
+/// Pipes from the job to the shim, which writes the ID maps of the job's
+/// user namespace. A process cannot map host IDs other than its own for itself.
+struct IdMapChannel {
+    /// Job writes a byte when its user namespace has been created
+    request: libc::c_int,
+    /// Shim writes a byte when the maps have been written
+    done: libc::c_int,
+}
+impl IdMapChannel {
+    /// Wait until the shim has written the ID maps
+    unsafe fn request_id_map(self) -> io::Result<()> {
+        let mut byte = 0u8;
+        let result = if libc::write(self.request, &byte as *const u8 as *const libc::c_void, 1) == 1
+            && libc::read(self.done, &mut byte as *mut u8 as *mut libc::c_void, 1) == 1
+        {
+            Ok(())
+        } else {
+            // The shim closes the pipe if writing the maps fails
+            Err(io::Error::from_raw_os_error(libc::EPERM))
+        };
+        libc::close(self.request);
+        libc::close(self.done);
+        result
+    }
+}
+
+/// How much less CPU time than the hard limit counts as reaching it
+const CPU_LIMIT_MARGIN_MICROS: u64 = 100_000;
+
+/// Forks the job process. Returns in the new child process.
+///
+/// The original process stays as a shim between the server and the job:
+/// it waits until the job completes and exits with the same status,
+/// so that the server sees the job as its own child process.
+/// If the shim is killed, the job is killed too.
+///
+/// If `id_map` is set, the shim writes it as the UID and GID map of the job
+/// when requested through the returned channel.
+///
+/// As PID 1 of its namespace, the job ignores `SIGXCPU` and is only killed with
+/// `SIGKILL` at the hard limit. If the job was killed with `SIGKILL` after using
+/// `cpu_limit` seconds of CPU time, i.e. the hard limit, the shim reports `SIGXCPU`
+/// instead. Kills by the server or the OOM killer are told apart by the server.
+unsafe fn fork_shim(
+    id_map: Option<&CString>,
+    cpu_limit: Option<u64>,
+) -> io::Result<Option<IdMapChannel>> {
+    let mut request = [-1; 2];
+    let mut done = [-1; 2];
+    if id_map.is_some() {
+        check(libc::pipe2(request.as_mut_ptr(), libc::O_CLOEXEC))?;
+        check(libc::pipe2(done.as_mut_ptr(), libc::O_CLOEXEC))?;
+    }
+
+    let pid = check(libc::fork())?;
+    if pid == 0 {
+        check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+        return Ok(id_map.map(|_| {
+            libc::close(request[0]);
+            libc::close(done[1]);
+            IdMapChannel {
+                request: request[1],
+                done: done[0],
+            }
+        }));
+    }
+
+    // The shim never returns to the exec, so it must not hold the pipe
+    // that the server uses to detect exec failures, or any other files
+    if let Some(id_map) = id_map {
+        let (request, done) = (request[0], done[1]);
+        close_fds_except(request, done);
+        let mut byte = 0u8;
+        // Nothing is read if the job fails before creating the namespace
+        if libc::read(request, &mut byte as *mut u8 as *mut libc::c_void, 1) == 1
+            && write_proc_file(pid, b"uid_map", id_map.as_bytes()).is_ok()
+            && write_proc_file(pid, b"gid_map", id_map.as_bytes()).is_ok()
+        {
+            libc::write(done, &byte as *const u8 as *const libc::c_void, 1);
+        }
+        libc::close(request);
+        libc::close(done);
+    } else {
+        close_fds_from(3);
+    }
+
+    let mut status = 0;
+    let mut usage: libc::rusage = std::mem::zeroed();
+    while libc::wait4(pid, &mut status, 0, &mut usage) < 0 {
+        if *libc::__errno_location() != libc::EINTR {
+            libc::_exit(127);
+        }
+    }
+
+    if libc::WIFSIGNALED(status) {
+        // Die from the same signal, without dumping core
+        let mut signal = libc::WTERMSIG(status);
+        let cpu_micros = (usage.ru_utime.tv_sec + usage.ru_stime.tv_sec) as u64 * 1_000_000
+            + (usage.ru_utime.tv_usec + usage.ru_stime.tv_usec) as u64;
+        // The usage can be a few scheduler ticks below what the kernel compared to the limit
+        let at_limit = matches!(cpu_limit, Some(limit)
+            if cpu_micros + CPU_LIMIT_MARGIN_MICROS >= limit.saturating_mul(1_000_000));
+        if signal == libc::SIGKILL && at_limit {
+            signal = libc::SIGXCPU;
+        }
+        let no_core = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+        libc::signal(signal, libc::SIG_DFL);
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, signal);
+        libc::sigprocmask(libc::SIG_UNBLOCK, &set, ptr::null_mut());
+        libc::kill(libc::getpid(), signal);
+        libc::_exit(128 + signal);
+    }
+    libc::_exit(libc::WEXITSTATUS(status));
+}
+
+/// Format a number in decimal into the end of the buffer without allocating, returning the digits
+pub fn decimal(n: u32, buf: &mut [u8; 10]) -> &[u8] {
+    let mut start = buf.len();
+    let mut n = n;
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    &buf[start..]
+}
+
+/// Write to `/proc/<pid>/<file>` without allocating
+unsafe fn write_proc_file(pid: libc::pid_t, file: &[u8], content: &[u8]) -> io::Result<()> {
+    let mut digits = [0u8; 10];
+    let pid = decimal(pid as u32, &mut digits);
+
+    // Zeroed, so the path stays nul-terminated
+    let mut path = [0u8; 64];
+    let mut len = 0;
+    for part in &[b"/proc/", pid, b"/", file] {
+        path[len..len + part.len()].copy_from_slice(part);
+        len += part.len();
+    }
+
+    let fd = check(libc::open(
+        path.as_ptr() as *const libc::c_char,
+        libc::O_WRONLY | libc::O_CLOEXEC,
+    ))?;
+    let result = libc::write(fd, content.as_ptr() as *const libc::c_void, content.len());
+    libc::close(fd);
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Close all file descriptors starting from `first`
+unsafe fn close_fds_from(first: libc::c_int) {
+    close_fd_range(first, libc::c_int::MAX);
+}
+
+/// Close all file descriptors starting from 3, except `a` and `b`
+unsafe fn close_fds_except(a: libc::c_int, b: libc::c_int) {
+    let (low, high) = (a.min(b), a.max(b));
+    close_fd_range(3, low - 1);
+    close_fd_range(low + 1, high - 1);
+    close_fd_range(high + 1, libc::c_int::MAX);
+}
+
+/// Close file descriptors from `first` to `last`, inclusive
+unsafe fn close_fd_range(first: libc::c_int, last: libc::c_int) {
+    if first > last {
+        return;
+    }
+    if libc::syscall(libc::SYS_close_range, first, last as libc::c_uint, 0) == 0 {
+        return;
+    }
+    // Fallback for kernels older than 5.9
+    let max = libc::sysconf(libc::_SC_OPEN_MAX).min(last as libc::c_long + 1);
+    for fd in first..(max as libc::c_int) {
+        libc::close(fd);
+    }
+}
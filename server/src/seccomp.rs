@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use seccompiler::{BpfProgram, BpfProgramRef, TargetArch};
+
+/// Compiled seccomp filters by profile name
+pub type Profiles = HashMap<String, BpfProgram>;
+
+/// Compile the profiles of a seccompiler JSON file for the current architecture
+pub fn load(path: &Path) -> Result<Profiles, String> {
+    let arch = TargetArch::try_from(std::env::consts::ARCH)
+        .map_err(|e| format!("Seccomp is not supported: {}", e))?;
+    let file = File::open(path)
+        .map_err(|e| format!("Reading seccomp profiles {:?} failed: {}", path, e))?;
+    seccompiler::compile_from_json(file, arch)
+        .map_err(|e| format!("Invalid seccomp profiles in {:?}: {}", path, e))
+}
+
+/// Install a filter for the calling thread.
+pub unsafe fn install(program: BpfProgramRef) -> io::Result<()> {
+    let fprog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        // Same layout as `libc::sock_filter`
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    let result = libc::syscall(
+        libc::SYS_seccomp,
+        libc::SECCOMP_SET_MODE_FILTER,
+        0,
+        &fprog as *const libc::sock_fprog,
+    );
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
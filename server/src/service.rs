@@ -1,22 +1,37 @@
 use std::collections::HashMap;
+use std::path::Path;
 use std::pin::Pin;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
+use tokio::io::AsyncWriteExt;
 use tokio_stream::Stream;
-use tonic::{Request, Response, Status};
+use tonic::{Request, Response, Status, Streaming};
 
 use common::t_service_server::TService;
 use common::*;
 
+use crate::capabilities;
+use crate::cgroup::CgroupRoot;
 use crate::client_cert::ClientName;
-use crate::job::Job;
+use crate::config::Config;
+use crate::cpuset;
+use crate::environment::Environment;
+use crate::job::{Job, Reserved};
+use crate::landlock;
+use crate::mounts;
+use crate::network::Bridge;
+use crate::oci;
 use crate::output_stream;
+use crate::sandbox;
+use crate::tmpfs::{self, Scratch};
+use crate::userns::IdAllocator;
+use crate::users::Credentials;
 
 type BoxStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send + Sync + 'static>>;
 
 /// Enforce authentication, return client CN from the certificate
 fn authenticate<T>(request: &Request<T>) -> Result<ClientName, Status> {
-    match ClientName::from_request(&request) {
+    match ClientName::from_request(request) {
         Some(name) => {
             log::info!("Authenticated as {:?}", name);
             Ok(name)
@@ -42,12 +57,28 @@ fn verify_authorized(client_name: &ClientName, job: &Job) -> Result<(), Status>
 
 pub struct TServiceImpl {
     state: Mutex<HashMap<JobId, Job>>,
+    config: Config,
+    /// Parent of the job cgroups, if cgroups are available
+    cgroups: Option<CgroupRoot>,
+    /// Bridge for jobs, if bridged networking is available
+    bridge: Option<Arc<Bridge>>,
+    /// Host IDs for user namespaces, if configured
+    ids: Option<Arc<IdAllocator>>,
 }
 
 impl TServiceImpl {
-    pub fn new() -> Self {
+    pub fn new(
+        config: Config,
+        cgroups: Option<CgroupRoot>,
+        bridge: Option<Bridge>,
+        ids: Option<IdAllocator>,
+    ) -> Self {
         Self {
             state: Mutex::new(HashMap::new()),
+            config,
+            cgroups,
+            bridge: bridge.map(Arc::new),
+            ids: ids.map(Arc::new),
         }
     }
 
@@ -77,7 +108,7 @@ impl TServiceImpl {
     {
         if let Ok(jobid) = JobId::from_bytes(&target_jobid.jobid) {
             self.with_job(jobid, |job| {
-                verify_authorized(&client_name, job)?;
+                verify_authorized(client_name, job)?;
                 f(job)
             })
         } else {
@@ -86,12 +117,6 @@ impl TServiceImpl {
     }
 }
 
-impl Default for TServiceImpl {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
 #[tonic::async_trait]
 impl TService for TServiceImpl {
     /// Spawn a new job
@@ -101,10 +126,155 @@ impl TService for TServiceImpl {
     ) -> Result<Response<TargetJobId>, Status> {
         let client_name = authenticate(&request)?;
 
-        match Job::spawn(client_name, request.into_inner()) {
+        let mut req = request.into_inner();
+
+        // Converted to request fields first, so that the policy checks below apply to bundles too
+        if !req.bundle.is_empty() {
+            let bundle = oci::resolve(Path::new(&req.bundle)).map_err(Status::invalid_argument)?;
+            if !mounts::is_allowed(&bundle, &self.config.policy.bundles) {
+                return Err(Status::permission_denied(format!(
+                    "Bundle {:?} is not allowed",
+                    req.bundle
+                )));
+            }
+            oci::apply(&bundle, &mut req).map_err(Status::invalid_argument)?;
+        }
+
+        if req.network == NetworkMode::Host as i32 && !self.config.policy.allow_host_network {
+            return Err(Status::permission_denied("Host network is not allowed"));
+        }
+
+        // Validated when the config is loaded
+        let allowed = capabilities::parse_set(&self.config.policy.allowed_capabilities).unwrap();
+        for name in &req.capabilities {
+            let cap = capabilities::parse(name).map_err(Status::invalid_argument)?;
+            if allowed & (1 << cap) == 0 {
+                return Err(Status::permission_denied(format!(
+                    "Capability {} is not allowed",
+                    name
+                )));
+            }
+        }
+
+        for mount in &req.mounts {
+            let source = mounts::resolve_source(&mount.source).map_err(Status::invalid_argument)?;
+            if !mounts::is_allowed(&source.path, &self.config.policy.mount_sources) {
+                return Err(Status::permission_denied(format!(
+                    "Mounting {:?} is not allowed",
+                    mount.source
+                )));
+            }
+        }
+
+        if !req.rootfs.is_empty() {
+            let root = sandbox::resolve_rootfs(Path::new(&req.rootfs))
+                .map_err(Status::failed_precondition)?;
+            if !mounts::is_allowed(&root, &self.config.policy.rootfs) {
+                return Err(Status::permission_denied(format!(
+                    "Root filesystem {:?} is not allowed",
+                    req.rootfs
+                )));
+            }
+        }
+        if req.writable_rootfs && !self.config.policy.allow_writable_rootfs {
+            return Err(Status::permission_denied(
+                "Writable root filesystem is not allowed",
+            ));
+        }
+
+        if !req.path_rules.is_empty() && landlock::abi_version().is_none() {
+            return Err(Status::failed_precondition(
+                "Path rules require Landlock, which is not supported by the kernel",
+            ));
+        }
+
+        let credentials = if let Some(user) = &req.user {
+            let credentials = Credentials::resolve(user).map_err(Status::invalid_argument)?;
+            let allowed = match self.config.users.get(client_name.as_str()) {
+                Some(policy) => credentials
+                    .allowed_by(policy)
+                    .map_err(Status::failed_precondition)?,
+                None => false,
+            };
+            if !allowed {
+                log::warn!(
+                    "Client {:?} tried to run a job as user {:?}",
+                    client_name,
+                    user.user
+                );
+                return Err(Status::permission_denied("User or group is not allowed"));
+            }
+            Some(credentials)
+        } else {
+            None
+        };
+
+        let id_mapping = if req.user_namespace {
+            let ids = self
+                .ids
+                .as_ref()
+                .ok_or_else(|| Status::failed_precondition("User namespaces are not configured"))?;
+            Some(ids.allocate().map_err(Status::resource_exhausted)?)
+        } else {
+            None
+        };
+
+        let jobid = JobId::new();
+        let environment = Environment::new(jobid, &req).map_err(Status::invalid_argument)?;
+
+        let network = if req.network == NetworkMode::Bridge as i32 {
+            let bridge = self.bridge.as_ref().ok_or_else(|| {
+                Status::failed_precondition("Bridged networking is not available")
+            })?;
+            let attachment = bridge
+                .attach(jobid)
+                .await
+                .map_err(Status::failed_precondition)?;
+            Some(attachment)
+        } else {
+            None
+        };
+
+        let owner = tmpfs::job_owner(credentials.as_ref(), id_mapping.as_ref())
+            .map_err(Status::invalid_argument)?;
+        let tmpfs = req
+            .tmpfs
+            .iter()
+            .enumerate()
+            .map(|(index, tmpfs)| Scratch::create(jobid, index, tmpfs, owner))
+            .collect::<Result<_, _>>()
+            .map_err(Status::invalid_argument)?;
+
+        // Hold the lock until the job is added, so that concurrent
+        // requests cannot reserve the same CPUs
+        let mut jobs = self.state.lock().unwrap();
+
+        if let Some(set) = req.cpuset.as_ref().filter(|set| set.exclusive) {
+            let cpus = cpuset::parse_list(&set.cpus).map_err(Status::invalid_argument)?;
+            if cpus.is_empty() {
+                return Err(Status::invalid_argument("Exclusive CPU set is empty"));
+            }
+            if jobs.values().any(|job| job.reserves_any(&cpus)) {
+                return Err(Status::failed_precondition(
+                    "CPUs are reserved exclusively by another job",
+                ));
+            }
+        }
+
+        match Job::spawn(
+            jobid,
+            client_name,
+            req,
+            &self.config,
+            self.cgroups.as_ref(),
+            Reserved {
+                network,
+                id_mapping,
+                tmpfs,
+            },
+            environment,
+        ) {
             Ok(job) => {
-                let jobid = JobId::new();
-                let mut jobs = self.state.lock().unwrap();
                 jobs.insert(jobid, job);
                 Ok(Response::new(jobid.into()))
             }
@@ -112,16 +282,83 @@ impl TService for TServiceImpl {
         }
     }
 
-    /// Starts killing the child process, but doesn't wait until it's actually stopped
+    /// Kills all processes of the job, and waits until they're gone
     async fn stop(
         &self,
         request: Request<TargetJobId>,
     ) -> Result<Response<StopSignalSent>, Status> {
         let client_name = authenticate(&request)?;
-        self.target_job(request.into_inner(), &client_name, |job| {
+        let completion = self.target_job(request.into_inner(), &client_name, |job| {
             job.start_kill();
-            Ok(Response::new(StopSignalSent {}))
-        })
+            Ok(job.completion())
+        })?;
+        completion.await;
+        Ok(Response::new(StopSignalSent {}))
+    }
+
+    /// Freeze all processes of a job, and wait until they're frozen
+    async fn pause(&self, request: Request<TargetJobId>) -> Result<Response<JobPaused>, Status> {
+        let client_name = authenticate(&request)?;
+        let cgroup = self.target_job(request.into_inner(), &client_name, |job| {
+            job.freezer().map_err(Status::failed_precondition)
+        })?;
+        cgroup
+            .set_frozen(true)
+            .await
+            .map_err(|e| Status::internal(format!("Freezing job failed: {}", e)))?;
+        Ok(Response::new(JobPaused {}))
+    }
+
+    /// Thaw the processes of a paused job, and wait until they're running again
+    async fn resume(&self, request: Request<TargetJobId>) -> Result<Response<JobResumed>, Status> {
+        let client_name = authenticate(&request)?;
+        let cgroup = self.target_job(request.into_inner(), &client_name, |job| {
+            job.freezer().map_err(Status::failed_precondition)
+        })?;
+        cgroup
+            .set_frozen(false)
+            .await
+            .map_err(|e| Status::internal(format!("Thawing job failed: {}", e)))?;
+        Ok(Response::new(JobResumed {}))
+    }
+
+    /// Write a stream of data to stdin of a job
+    async fn write_stdin(
+        &self,
+        request: Request<Streaming<StdinChunk>>,
+    ) -> Result<Response<StdinWritten>, Status> {
+        let client_name = authenticate(&request)?;
+        let mut chunks = request.into_inner();
+
+        let mut stdin = None;
+        let mut written = 0;
+        while let Some(chunk) = chunks.message().await? {
+            let pipe = match &stdin {
+                Some(pipe) => pipe,
+                None => {
+                    let target = TargetJobId { jobid: chunk.jobid };
+                    let pipe = self.target_job(target, &client_name, |job| {
+                        job.stdin().map_err(Status::failed_precondition)
+                    })?;
+                    stdin.insert(pipe)
+                }
+            };
+            // Locked for a single chunk, so that an idle stream doesn't block other writers
+            let mut pipe = pipe.lock().await;
+            let writer = pipe
+                .as_mut()
+                .ok_or_else(|| Status::failed_precondition("Stdin has been closed"))?;
+            writer
+                .write_all(&chunk.data)
+                .await
+                .map_err(|e| Status::failed_precondition(format!("Writing stdin failed: {}", e)))?;
+            written += chunk.data.len() as u64;
+            if chunk.close {
+                // Dropping the pipe closes it
+                *pipe = None;
+            }
+        }
+        Ok(Response::new(StdinWritten { bytes: written }))
     }
 
     /// Get status of a job
@@ -153,4 +390,56 @@ impl TService for TServiceImpl {
         let s = tokio_stream::wrappers::ReceiverStream::new(rx);
         Ok(Response::new(Box::pin(s)))
     }
+
+    type AttachStream = BoxStream<OutputEvent>;
+
+    /// Forward input to the terminal of a job, and stream the output of the terminal.
+    /// The output stream ends when the job has completed.
+    async fn attach(
+        &self,
+        request: Request<Streaming<AttachInput>>,
+    ) -> Result<Response<Self::AttachStream>, Status> {
+        let client_name = authenticate(&request)?;
+        let mut input = request.into_inner();
+
+        let first = input
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("No job selected"))?;
+        let target = TargetJobId {
+            jobid: first.jobid.clone(),
+        };
+        let (terminal, stdout) = self.target_job(target, &client_name, |job| {
+            let terminal = job.terminal().map_err(Status::failed_precondition)?;
+            Ok((terminal, job.stdout.clone()))
+        })?;
+
+        let (tx, rx) = tokio::sync::mpsc::channel(2);
+        output_stream::stream_to(stdout, tx);
+
+        tokio::spawn(async move {
+            let mut next = Some(first);
+            while let Some(message) = next {
+                let result = match message.input {
+                    Some(attach_input::Input::Data(data)) => terminal.write_all(&data).await,
+                    Some(attach_input::Input::Resize(size)) => terminal.resize(&size),
+                    None => Ok(()),
+                };
+                if let Err(err) = result {
+                    log::debug!("Terminal input failed, ending stream: {}", err);
+                    break;
+                }
+                next = match input.message().await {
+                    Ok(message) => message,
+                    Err(err) => {
+                        log::debug!("Attach input stream failed: {}", err);
+                        None
+                    }
+                };
+            }
+        });
+
+        let s = tokio_stream::wrappers::ReceiverStream::new(rx);
+        Ok(Response::new(Box::pin(s)))
+    }
 }
@@ -0,0 +1,140 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use common::{JobId, Tmpfs, TmpfsUsage};
+
+use crate::userns::IdMapping;
+use crate::users::Credentials;
+
+/// Host directory under which the tmpfs mounts of jobs are created.
+/// Only accessible by the server, so that other host users cannot reach the files of jobs.
+const TMPFS_ROOT: &str = "/run/job-runner/tmpfs";
+
+/// Host UID and GID that the job process runs as.
+/// Fails if the IDs are not mapped by the user namespace of the job.
+pub fn job_owner(
+    credentials: Option<&Credentials>,
+    id_mapping: Option<&IdMapping>,
+) -> Result<(libc::uid_t, libc::gid_t), String> {
+    let (uid, gid) = match credentials {
+        Some(credentials) => (credentials.uid, credentials.gid),
+        // Root of the user namespace
+        None if id_mapping.is_some() => (0, 0),
+        None => unsafe { (libc::getuid(), libc::getgid()) },
+    };
+    match id_mapping {
+        Some(mapping) => {
+            let host_id = |id: u32| {
+                Some(id)
+                    .filter(|&id| id < mapping.size)
+                    .and_then(|id| mapping.host_id.checked_add(id))
+                    .ok_or_else(|| format!("ID {} is not mapped in the user namespace", id))
+            };
+            Ok((host_id(uid)?, host_id(gid)?))
+        }
+        None => Ok((uid, gid)),
+    }
+}
+
+/// A tmpfs of a single job, unmounted when dropped.
+///
+/// The tmpfs is mounted on the host and bind-mounted into the job,
+/// so that its usage can still be read after the job mount namespace is gone.
+#[derive(Debug)]
+pub struct Scratch {
+    /// Mount point on the host
+    pub path: PathBuf,
+    /// Mount point in the job filesystem
+    pub destination: String,
+    size_bytes: u64,
+}
+impl Scratch {
+    /// The root directory of the tmpfs is owned by `owner`, and only accessible by it
+    pub fn create(
+        jobid: JobId,
+        index: usize,
+        tmpfs: &Tmpfs,
+        owner: (libc::uid_t, libc::gid_t),
+    ) -> Result<Self, String> {
+        if tmpfs.size_bytes == 0 {
+            return Err(format!("Size of tmpfs {:?} is not set", tmpfs.destination));
+        }
+        if !Path::new(&tmpfs.destination).is_absolute() {
+            return Err(format!(
+                "Tmpfs destination {:?} is not an absolute path",
+                tmpfs.destination
+            ));
+        }
+
+        let error = |e: io::Error| format!("Creating tmpfs mount point failed: {}", e);
+        fs::create_dir_all(TMPFS_ROOT).map_err(error)?;
+        fs::set_permissions(TMPFS_ROOT, fs::Permissions::from_mode(0o700)).map_err(error)?;
+        let path = Path::new(TMPFS_ROOT).join(format!("job-{}-{}", jobid, index));
+        fs::create_dir(&path).map_err(error)?;
+        let scratch = Self {
+            path,
+            destination: tmpfs.destination.clone(),
+            size_bytes: tmpfs.size_bytes,
+        };
+
+        let target = CString::new(scratch.path.as_os_str().as_bytes()).unwrap();
+        let options = CString::new(format!(
+            "size={},mode=0700,uid={},gid={}",
+            tmpfs.size_bytes, owner.0, owner.1
+        ))
+        .unwrap();
+        let result = unsafe {
+            libc::mount(
+                "tmpfs\0".as_ptr() as *const libc::c_char,
+                target.as_ptr(),
+                "tmpfs\0".as_ptr() as *const libc::c_char,
+                libc::MS_NOSUID | libc::MS_NODEV,
+                options.as_ptr() as *const libc::c_void,
+            )
+        };
+        if result < 0 {
+            return Err(format!(
+                "Mounting tmpfs failed: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        Ok(scratch)
+    }
+
+    /// Space currently used in the tmpfs
+    pub fn usage(&self) -> TmpfsUsage {
+        let path = CString::new(self.path.as_os_str().as_bytes()).unwrap();
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        let used_bytes = if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } == 0 {
+            (stat.f_blocks - stat.f_bfree) * stat.f_frsize
+        } else {
+            log::warn!("Could not read tmpfs usage: {}", io::Error::last_os_error());
+            0
+        };
+        TmpfsUsage {
+            destination: self.destination.clone(),
+            used_bytes,
+            size_bytes: self.size_bytes,
+        }
+    }
+}
+
+impl Drop for Scratch {
+    /// Unmount the tmpfs, which frees its contents, and remove the mount point
+    fn drop(&mut self) {
+        let path = CString::new(self.path.as_os_str().as_bytes()).unwrap();
+        if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EINVAL) {
+                log::warn!("Could not unmount job tmpfs: {}", err);
+            }
+        }
+        if let Err(err) = fs::remove_dir(&self.path) {
+            log::warn!("Could not remove job tmpfs mount point: {}", err);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use tokio::io::unix::AsyncFd;
+
+use common::ResourceUsage;
+
+use crate::cgroup::Cgroup;
+
+/// Total bytes read and written, over all devices in `io.stat`
+fn io_bytes(cgroup: &Cgroup) -> Option<(u64, u64)> {
+    let stat = cgroup.read("io.stat").ok()?;
+    let mut totals = (0, 0);
+    for field in stat.split_whitespace() {
+        let (key, value) = match field.split_once('=') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let value: u64 = value.parse().unwrap_or(0);
+        match key {
+            "rbytes" => totals.0 += value,
+            "wbytes" => totals.1 += value,
+            _ => {}
+        }
+    }
+    Some(totals)
+}
+
+/// Microseconds of a `timeval`
+fn micros(time: &libc::timeval) -> u64 {
+    time.tv_sec as u64 * 1_000_000 + time.tv_usec as u64
+}
+
+/// Usage of the processes in a job cgroup, and of the processes the job has waited for.
+/// The values of the cgroup are used when available, as they cover all processes.
+pub fn collect(cgroup: Option<&Cgroup>, rusage: Option<&libc::rusage>) -> Option<ResourceUsage> {
+    if cgroup.is_none() && rusage.is_none() {
+        return None;
+    }
+    let keyed = |file, key| cgroup.and_then(|c| c.read_keyed(file, key).ok());
+    let value = |file| cgroup.and_then(|c| c.read_value(file).ok());
+    let io = cgroup.and_then(io_bytes);
+
+    Some(ResourceUsage {
+        cpu_user_us: keyed("cpu.stat", "user_usec")
+            .or_else(|| rusage.map(|r| micros(&r.ru_utime)))
+            .unwrap_or(0),
+        cpu_system_us: keyed("cpu.stat", "system_usec")
+            .or_else(|| rusage.map(|r| micros(&r.ru_stime)))
+            .unwrap_or(0),
+        memory_peak_bytes: value("memory.peak")
+            .or_else(|| rusage.map(|r| r.ru_maxrss as u64 * 1024))
+            .unwrap_or(0),
+        // Block counts of rusage are in 512-byte units
+        io_read_bytes: io
+            .map(|(read, _)| read)
+            .or_else(|| rusage.map(|r| r.ru_inblock as u64 * 512))
+            .unwrap_or(0),
+        io_write_bytes: io
+            .map(|(_, write)| write)
+            .or_else(|| rusage.map(|r| r.ru_oublock as u64 * 512))
+            .unwrap_or(0),
+        processes_peak: value("pids.peak").unwrap_or(0),
+    })
+}
+
+/// Wait until a child process has exited, and return its resource usage.
+/// The process is left as a zombie, so that it can still be reaped normally.
+pub async fn wait_exited(pid: libc::pid_t) -> io::Result<libc::rusage> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) } as libc::c_int;
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // A pidfd becomes readable when the process exits
+    let pidfd = AsyncFd::new(unsafe { File::from_raw_fd(fd) })?;
+    loop {
+        let mut guard = pidfd.readable().await?;
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+        // The libc wrapper of waitid has no rusage argument
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_waitid,
+                libc::P_PIDFD,
+                pidfd.as_raw_fd(),
+                &mut info as *mut libc::siginfo_t,
+                libc::WEXITED | libc::WNOWAIT | libc::WNOHANG,
+                &mut rusage as *mut libc::rusage,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { info.si_pid() } != 0 {
+            return Ok(rusage);
+        }
+        guard.clear_ready();
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use crate::config::UserNamespaceConfig;
+
+/// Checks that the ranges don't overlap, and that they don't contain root
+/// or the IDs of the server, which jobs must not be mapped to
+pub fn validate(config: &UserNamespaceConfig) -> Result<(), String> {
+    if config.ids_per_job == 0 {
+        return Err("ids_per_job must not be zero".to_owned());
+    }
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for range in &config.ranges {
+        let end = range.start.checked_add(range.count).ok_or_else(|| {
+            format!(
+                "User namespace ID range {}+{} is too large",
+                range.start, range.count
+            )
+        })?;
+        if range.start == 0 {
+            return Err("User namespace ID ranges must not contain root".to_owned());
+        }
+        for id in &[uid, gid] {
+            if (range.start..end).contains(id) {
+                return Err(format!(
+                    "User namespace ID range {}+{} contains ID {} of the server",
+                    range.start, range.count, id
+                ));
+            }
+        }
+        if let Some((start, other_end)) = ranges
+            .iter()
+            .find(|&&(start, other_end)| range.start < other_end && start < end)
+        {
+            return Err(format!(
+                "User namespace ID ranges {}+{} and {}+{} overlap",
+                start,
+                other_end - start,
+                range.start,
+                range.count
+            ));
+        }
+        ranges.push((range.start, end));
+    }
+    Ok(())
+}
+
+/// Hands out blocks of host IDs for user namespaces,
+/// so that jobs never share IDs with each other
+#[derive(Debug)]
+pub struct IdAllocator {
+    /// Start and end (exclusive) of the usable host ID ranges
+    ranges: Vec<(u32, u32)>,
+    ids_per_job: u32,
+    /// First host IDs of the blocks in use
+    allocated: Mutex<BTreeSet<u32>>,
+}
+impl IdAllocator {
+    /// The config must have been checked with `validate`
+    pub fn new(config: &UserNamespaceConfig) -> Self {
+        Self {
+            ranges: config
+                .ranges
+                .iter()
+                .map(|range| (range.start, range.start + range.count))
+                .collect(),
+            ids_per_job: config.ids_per_job,
+            allocated: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Reserve the lowest free block of IDs
+    pub fn allocate(self: &Arc<Self>) -> Result<IdMapping, String> {
+        let mut allocated = self.allocated.lock().unwrap();
+        let size = self.ids_per_job;
+        let host_id = self
+            .ranges
+            .iter()
+            .flat_map(|&(start, end)| {
+                (start..end)
+                    .step_by(size as usize)
+                    .take_while(move |id| end - id >= size)
+            })
+            .find(|id| !allocated.contains(id))
+            .ok_or_else(|| "No free user namespace ID ranges".to_owned())?;
+        allocated.insert(host_id);
+        Ok(IdMapping {
+            host_id,
+            size,
+            allocator: self.clone(),
+        })
+    }
+}
+
+/// Host IDs of a single job, released when dropped
+#[derive(Debug)]
+pub struct IdMapping {
+    pub host_id: u32,
+    pub size: u32,
+    allocator: Arc<IdAllocator>,
+}
+impl IdMapping {
+    /// Contents for `uid_map` and `gid_map`
+    pub fn map_line(&self) -> String {
+        format!("0 {} {}\n", self.host_id, self.size)
+    }
+
+    pub fn to_status(&self) -> common::IdMapping {
+        common::IdMapping {
+            host_id: self.host_id,
+            size: self.size,
+        }
+    }
+}
+
+impl Drop for IdMapping {
+    fn drop(&mut self) {
+        self.allocator
+            .allocated
+            .lock()
+            .unwrap()
+            .remove(&self.host_id);
+    }
+}
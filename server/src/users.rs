@@ -0,0 +1,162 @@
+use std::ffi::CString;
+use std::io;
+use std::ptr;
+
+use common::User;
+
+use crate::config::UserPolicy;
+
+/// Buffer size for the strings of a passwd or group entry
+const ENTRY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Uid and primary gid of a user in `/etc/passwd`
+fn lookup_user(name: &str) -> Result<Option<(libc::uid_t, libc::gid_t)>, String> {
+    let c_name = CString::new(name).map_err(|_| format!("Invalid user name {:?}", name))?;
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let ret = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 {
+        return Err(format!(
+            "Looking up user {:?} failed: {}",
+            name,
+            io::Error::from_raw_os_error(ret)
+        ));
+    }
+    Ok(if result.is_null() {
+        None
+    } else {
+        Some((entry.pw_uid, entry.pw_gid))
+    })
+}
+
+/// Primary gid of a uid in `/etc/passwd`
+fn lookup_primary_gid(uid: libc::uid_t) -> Option<libc::gid_t> {
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let ret = unsafe {
+        libc::getpwuid_r(
+            uid,
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        None
+    } else {
+        Some(entry.pw_gid)
+    }
+}
+
+/// Gid of a group in `/etc/group`
+fn lookup_group(name: &str) -> Result<Option<libc::gid_t>, String> {
+    let c_name = CString::new(name).map_err(|_| format!("Invalid group name {:?}", name))?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let ret = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 {
+        return Err(format!(
+            "Looking up group {:?} failed: {}",
+            name,
+            io::Error::from_raw_os_error(ret)
+        ));
+    }
+    Ok(if result.is_null() {
+        None
+    } else {
+        Some(entry.gr_gid)
+    })
+}
+
+/// Resolve a user name or a numeric uid
+fn resolve_uid(user: &str) -> Result<libc::uid_t, String> {
+    match user.parse() {
+        Ok(uid) => Ok(uid),
+        Err(_) => lookup_user(user)?
+            .map(|(uid, _)| uid)
+            .ok_or_else(|| format!("No such user: {}", user)),
+    }
+}
+
+/// Resolve a group name or a numeric gid
+fn resolve_gid(group: &str) -> Result<libc::gid_t, String> {
+    match group.parse() {
+        Ok(gid) => Ok(gid),
+        Err(_) => lookup_group(group)?.ok_or_else(|| format!("No such group: {}", group)),
+    }
+}
+
+/// Credentials the job runs with, resolved from names
+#[derive(Debug, PartialEq)]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    /// Supplementary groups
+    pub groups: Vec<libc::gid_t>,
+}
+impl Credentials {
+    /// Resolve the names in a request. The primary group defaults to the one in `/etc/passwd`.
+    pub fn resolve(user: &User) -> Result<Self, String> {
+        let uid = resolve_uid(&user.user)?;
+        let gid = if !user.group.is_empty() {
+            resolve_gid(&user.group)?
+        } else {
+            lookup_primary_gid(uid)
+                .ok_or_else(|| format!("User {} has no primary group, set one", user.user))?
+        };
+        let groups = user
+            .groups
+            .iter()
+            .map(|group| resolve_gid(group))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { uid, gid, groups })
+    }
+
+    /// Checks that the policy allows all of the uid and gids
+    pub fn allowed_by(&self, policy: &UserPolicy) -> Result<bool, String> {
+        let uids = policy
+            .users
+            .iter()
+            .map(|user| resolve_uid(user))
+            .collect::<Result<Vec<_>, _>>()?;
+        let gids = policy
+            .groups
+            .iter()
+            .map(|group| resolve_gid(group))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(uids.contains(&self.uid)
+            && gids.contains(&self.gid)
+            && self.groups.iter().all(|gid| gids.contains(gid)))
+    }
+
+    /// Switch to these credentials.
+    pub unsafe fn switch(&self) -> io::Result<()> {
+        if libc::setgroups(self.groups.len(), self.groups.as_ptr()) < 0
+            || libc::setresgid(self.gid, self.gid, self.gid) < 0
+            || libc::setresuid(self.uid, self.uid, self.uid) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}